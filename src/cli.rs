@@ -0,0 +1,137 @@
+//! Contains sued's command line parsing.
+//!
+//! This file is part of sued.
+//!
+//! Visit `main.rs` for context and usage.
+
+/// A line to run once sued has started, from `-e` or `-f`.
+/// `Command`s come from `-e`, and always run as a command, with or without the prefix.
+/// `Line`s come from `-f` files, and are commands or text, just like typed lines.
+pub enum StartupInput {
+    Command(String),
+    Line(String),
+}
+
+/// A file to open from the command line, with the line to put the cursor on, if one was given.
+pub struct FileArgument {
+    pub path: String,
+    pub line: Option<usize>,
+}
+
+/// Everything that can be set from the command line.
+#[derive(Default)]
+pub struct CliOptions {
+    pub files: Vec<FileArgument>,
+    pub startup_input: Vec<StartupInput>,
+    pub no_config: bool,
+    /// `Some(None)` serves over stdio, and `Some(Some(path))` serves over a socket at `path`.
+    pub server: Option<Option<String>>,
+    /// `Some(None)` restricts sued to the current directory, and `Some(Some(root))` to `root`.
+    pub restricted: Option<Option<String>>,
+    pub filter: bool,
+    pub readonly: bool,
+    pub quiet: bool,
+    pub help: bool,
+    pub version: bool,
+}
+
+/// Returns the usage text for `--help`.
+pub fn usage() -> String {
+    format!(
+"sued v{} - shut up editor
+
+usage: sued [options] [+line] [file[:line]]...
+
+  file[:line]        open each file in its own buffer, optionally at a line
+  +line              put the cursor on this line of the next file
+  -e command         run a command once sued has started, can be given more than once
+  -f script          run every line of a script once sued has started
+  -, --filter        read the buffer from stdin (or the file), run -e and -f, and write it to stdout
+  -R, --readonly     open files read-only
+  -q, --quiet        don't show the startup message
+  -r, --restricted[=root]
+                     no shell commands or plugins, and only files in root (or here) can be opened or saved
+  --no-config        don't read the config file
+  --server[=socket]  take JSON-RPC requests over stdio, or a unix socket
+  -h, --help         show this help
+  -V, --version      show the version
+
+visit https://aeriavelocity.github.io/sued for the full manual",
+        env!("CARGO_PKG_VERSION"))
+}
+
+/// Parses the command line `args`, not including the program name.
+/// Returns a message saying what's wrong if they don't make sense.
+pub fn parse_args(args: impl IntoIterator<Item = String>) -> Result<CliOptions, String> {
+    let mut options = CliOptions::default();
+    let mut next_line: Option<usize> = None;
+    let mut only_files = false;
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        if only_files || !arg.starts_with(['-', '+']) || arg == "+" {
+            options.files.push(file_argument(arg, next_line.take()));
+            continue;
+        }
+
+        match arg.as_str() {
+            "--" => only_files = true,
+            "-" | "--filter" => options.filter = true,
+            "-e" => {
+                let command = args.next().ok_or("-e needs a command to run")?;
+                options.startup_input.push(StartupInput::Command(command));
+            }
+            "-f" => {
+                let script_path = args.next().ok_or("-f needs a file to read commands from")?;
+                let script = std::fs::read_to_string(&script_path)
+                    .map_err(|e| format!("couldn't read {}, because {}", script_path, e))?;
+                options.startup_input.extend(script.lines().map(|line| StartupInput::Line(line.to_string())));
+            }
+            "-R" | "--readonly" => options.readonly = true,
+            "-q" | "--quiet" => options.quiet = true,
+            "--no-config" => options.no_config = true,
+            "--server" => options.server = Some(None),
+            "-r" | "--restricted" => options.restricted = Some(None),
+            "-h" | "--help" => options.help = true,
+            "-V" | "--version" => options.version = true,
+            _ => {
+                if let Some(socket_path) = arg.strip_prefix("--server=") {
+                    options.server = Some(Some(socket_path.to_string()));
+                }
+                else if let Some(root) = arg.strip_prefix("--restricted=") {
+                    options.restricted = Some(Some(root.to_string()));
+                }
+                else if let Some(line) = arg.strip_prefix('+') {
+                    let line = line.parse::<usize>().map_err(|_| format!("{} isn't a line number", line))?;
+                    next_line = Some(line);
+                }
+                else {
+                    return Err(format!("{} isn't an option, try --help", arg));
+                }
+            }
+        }
+    }
+
+    // A +line after the last file still means something, so it goes to the first one
+    if let (Some(line), Some(file)) = (next_line, options.files.first_mut()) {
+        file.line = file.line.or(Some(line));
+    }
+
+    Ok(options)
+}
+
+/// Splits a `file:line` argument into the file and the line.
+/// Files that really have a colon and a number at the end of their name are left alone.
+fn file_argument(arg: String, line: Option<usize>) -> FileArgument {
+    if let Some((path, line_number)) = arg.rsplit_once(':') {
+        if let Ok(line_number) = line_number.parse::<usize>() {
+            if !path.is_empty() && !std::path::Path::new(&arg).exists() {
+                return FileArgument {
+                    path: path.to_string(),
+                    line: Some(line_number),
+                };
+            }
+        }
+    }
+    FileArgument { path: arg, line }
+}
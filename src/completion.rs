@@ -0,0 +1,116 @@
+//! Contains sued's tab completion, which plugs into linefeed.
+//!
+//! This file is part of sued.
+//!
+//! Visit `main.rs` for context and usage.
+
+use std::collections::BTreeSet;
+use linefeed::{Completer, Completion, Prompter, Terminal};
+use linefeed::complete::complete_path;
+use crate::functions as suedfn;
+use crate::options;
+
+/// Commands that take a file path, and so get file path completion.
+const PATH_COMMANDS: [&str; 4] = ["open", "save", "script", "write"];
+
+/// Completes command names, file paths and words from the buffer.
+/// linefeed only holds onto the completer it's given, so `main` hands it a
+/// fresh one with the current `prefix`, buffer words, aliases, scripts and plugins before every line.
+/// In restricted mode, file paths aren't completed, since that would show what's outside the root directory.
+pub struct SuedCompleter {
+    prefix: String,
+    words: BTreeSet<String>,
+    extra_commands: Vec<String>,
+    complete_paths: bool,
+}
+
+impl SuedCompleter {
+    pub fn new(prefix: &str, buffer_contents: &[String], extra_commands: Vec<String>, complete_paths: bool) -> SuedCompleter {
+        let words = buffer_contents.iter()
+            .flat_map(|line| line.split(|c: char| !c.is_alphanumeric() && c != '_'))
+            .filter(|word| word.chars().count() >= 3)
+            .map(String::from)
+            .collect();
+        SuedCompleter {
+            prefix: prefix.to_string(),
+            words,
+            extra_commands,
+            complete_paths,
+        }
+    }
+
+    /// Completes `word` as a command, alias, script or plugin name, keeping the prefix if it's there.
+    fn complete_command(&self, word: &str) -> Vec<Completion> {
+        let (typed_prefix, name) = match word.strip_prefix(&self.prefix) {
+            Some(name) => (self.prefix.as_str(), name),
+            None => ("", word),
+        };
+        suedfn::COMMANDS.iter()
+            .copied()
+            .chain(self.extra_commands.iter().map(String::as_str))
+            .filter(|command| command.starts_with(&name.to_lowercase()))
+            .map(|command| Completion::simple(format!("{}{}", typed_prefix, command)))
+            .collect()
+    }
+
+    /// Completes `word` as a file path, expanding and then restoring a leading `~`.
+    fn complete_file_path(&self, word: &str) -> Vec<Completion> {
+        let expanded = shellexpand::tilde(word).to_string();
+        let home = expanded.strip_suffix(word.trim_start_matches('~')).filter(|_| word.starts_with('~'));
+        let mut completions = complete_path(&expanded);
+        if let Some(home) = home {
+            for completion in completions.iter_mut() {
+                if let Some(rest) = completion.completion.strip_prefix(home) {
+                    completion.completion = format!("~{}", rest);
+                }
+            }
+        }
+        completions
+    }
+
+    /// Completes `word` as an option name, for `~set` and `~setlocal`.
+    fn complete_option(&self, word: &str) -> Vec<Completion> {
+        options::OPTIONS.iter()
+            .filter(|option| option.name.starts_with(word))
+            .map(|option| Completion::simple(option.name.to_string()))
+            .collect()
+    }
+
+    /// Completes `word` from the words already in the buffer.
+    fn complete_buffer_word(&self, word: &str) -> Vec<Completion> {
+        if word.is_empty() {
+            return Vec::new();
+        }
+        self.words.iter()
+            .filter(|buffer_word| buffer_word.starts_with(word) && buffer_word.as_str() != word)
+            .map(|buffer_word| Completion::simple(buffer_word.clone()))
+            .collect()
+    }
+}
+
+impl<Term: Terminal> Completer<Term> for SuedCompleter {
+    fn complete(&self, word: &str, prompter: &Prompter<Term>, start: usize, _end: usize) -> Option<Vec<Completion>> {
+        let line = prompter.buffer();
+        if !line.starts_with(&self.prefix) {
+            return Some(self.complete_buffer_word(word));
+        }
+
+        // Only the command after the last `;` matters, since commands can be chained
+        let before_word = &line[..start];
+        let current_command = before_word.rsplit(';').next().unwrap_or_default().trim_start();
+        let current_command = current_command.strip_prefix(&self.prefix).unwrap_or(current_command);
+
+        match current_command.split_whitespace().next() {
+            None => Some(self.complete_command(word)),
+            Some(name) => match suedfn::resolve_command(name) {
+                suedfn::CommandName::Known(command) if self.complete_paths && PATH_COMMANDS.contains(&command) => {
+                    Some(self.complete_file_path(word))
+                }
+                suedfn::CommandName::Known("set" | "setlocal") if current_command.split_whitespace().count() == 1 => {
+                    Some(self.complete_option(word))
+                }
+                _ => Some(self.complete_buffer_word(word)),
+            },
+        }
+    }
+}
@@ -5,18 +5,25 @@
 //! Visit `main.rs` for context and usage.
 
 use std::io;
+use std::io::{IsTerminal, Write};
 use std::fs;
-use std::cmp::Ordering;
-use std::path::PathBuf;
-use std::process::Command;
+use std::env;
+use std::ops::Range;
+use std::path::{Component, Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::thread;
 use which::which;
 use rand::Rng;
 use regex::Regex;
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
 use copypasta::{ClipboardContext, ClipboardProvider};
+use crate::highlight;
+use crate::output;
+use crate::pager;
 
 /// Prints a startup message with a funny joke. I hope it's funny at least.
 /// Invoked at startup, obviously.
-pub fn startup_message() {
+pub fn startup_message(prefix: &str) {
     let messages: Vec<&str> = vec![
         "the shut up editor",
         "the not standard text editor",
@@ -28,81 +35,206 @@ pub fn startup_message() {
         "what you get is what you get",
         "what the frick is a config file",
         "less is more, much more",
-        "no config file means no config bankruptcy",
+        "optional config file means optional config bankruptcy",
         "free software, hell yeah",
         "put that mouse AWAY",
         "it looks like you're editing text, would you like help?",
         "who needs to save scripts to run them?",
         "startup_messages.push_str(&funny);",
-        "there's no scripting language, if you were wondering",
+        "there's a scripting language now, if you were wondering",
     ];
     let message: &str = messages[rand::thread_rng().gen_range(0..messages.len())];
     let version = env!("CARGO_PKG_VERSION");
-    println!("sued v{version} - {message}\ntype ~ for commands, otherwise just start typing");
+    say!("sued v{version} - {message}\ntype {prefix} for commands, otherwise just start typing");
 }
 
+/// Every command that sued supports, as listed by the `~` command.
+/// Abbreviations and suggestions are drawn from this list.
+pub const COMMANDS: [&str; 38] = [
+    "about",
+    "alias",
+    "around",
+    "buffer",
+    "clear",
+    "clipboard",
+    "copy",
+    "correct",
+    "delete",
+    "eval",
+    "exit",
+    "help",
+    "history",
+    "indent",
+    "insert",
+    "macro",
+    "open",
+    "paste",
+    "prefix",
+    "print",
+    "prompt",
+    "readonly",
+    "reopen",
+    "repeat",
+    "replace",
+    "retab",
+    "run",
+    "runhere",
+    "save",
+    "script",
+    "search",
+    "set",
+    "setlocal",
+    "show",
+    "substitute",
+    "swap",
+    "what",
+    "write",
+];
+
+/// Commands that work, but aren't listed and can't be abbreviated.
+/// These are either short aliases for listed commands or jokes.
+const HIDDEN_COMMANDS: [&str; 6] = [".", "bsod", "del", "nothing", "quit", "sub"];
+
 /// Displays and returns the list of commands that sued supports.
 /// Invoked with the `~` command.
 pub fn command_list() -> Vec<String> {
-    let commands = vec![
-        "about",
-        "clear",
-        "copy",
-        "correct",
-        "delete",
-        "exit",
-        "help",
-        "indent",
-        "insert",
-        "open",
-        "prefix",
-        "print",
-        "prompt",
-        "reopen",
-        "replace",
-        "run",
-        "runhere",
-        "save",
-        "search",
-        "show",
-        "substitute",
-        "swap",
-        "write"
-    ];
-    println!("{}", commands.join(", "));
-    commands.into_iter().map(|s| s.to_string()).collect()
+    say!("{}", COMMANDS.join(", "));
+    COMMANDS.into_iter().map(|s| s.to_string()).collect()
+}
+
+/// The result of looking up a command name with `resolve_command`.
+pub enum CommandName {
+    /// The full name of the command that was meant.
+    Known(&'static str),
+    /// Every command the abbreviation could stand for.
+    Ambiguous(Vec<&'static str>),
+    /// Commands with a similar name, closest first.
+    Unknown(Vec<&'static str>),
+}
+
+/// Works out which command `name` refers to.
+/// Exact names always win, otherwise `name` can be any unambiguous abbreviation of a listed command.
+/// Used by `process_command` before matching on the command name.
+pub fn resolve_command(name: &str) -> CommandName {
+    let name = name.to_lowercase();
+    if let Some(&command) = COMMANDS.iter().chain(HIDDEN_COMMANDS.iter()).find(|&&command| command == name) {
+        return CommandName::Known(command);
+    }
+
+    let candidates: Vec<&'static str> = COMMANDS.into_iter().filter(|command| !name.is_empty() && command.starts_with(&name)).collect();
+    match candidates.len() {
+        0 => {
+            let max_distance = (name.chars().count() / 3).max(1);
+            let mut suggestions: Vec<(usize, &'static str)> = COMMANDS.into_iter()
+                .map(|command| (edit_distance(&name, command), command))
+                .filter(|&(distance, _)| distance <= max_distance)
+                .collect();
+            suggestions.sort();
+            CommandName::Unknown(suggestions.into_iter().take(3).map(|(_, command)| command).collect())
+        }
+        1 => CommandName::Known(candidates[0]),
+        _ => CommandName::Ambiguous(candidates),
+    }
+}
+
+/// A helper function for command suggestions.
+/// Returns the edit distance between `a` and `b`, counted in characters,
+/// where swapping two neighbouring characters counts as one edit.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let mut distances = vec![vec![0; b.len() + 1]; a.len() + 1];
+
+    for (i, row) in distances.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    for (j, distance) in distances[0].iter_mut().enumerate() {
+        *distance = j;
+    }
+
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = usize::from(a[i - 1] != b[j - 1]);
+            let mut distance = (distances[i - 1][j] + 1)
+                .min(distances[i][j - 1] + 1)
+                .min(distances[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                distance = distance.min(distances[i - 2][j - 2] + 1);
+            }
+            distances[i][j] = distance;
+        }
+    }
+
+    distances[a.len()][b.len()]
+}
+
+/// A helper function for listing things in messages.
+/// Joins `items` like "a, b or c".
+pub fn join_with_or(items: &[&str]) -> String {
+    match items.split_last() {
+        Some((last, [])) => last.to_string(),
+        Some((last, rest)) => format!("{} or {}", rest.join(", "), last),
+        None => String::new(),
+    }
 }
 
 /// Displays a list of available commands and their descriptions.
 /// Invoked with the `~help` command.
 pub fn extended_command_list(prefix: &str) {
-    println!("{}",
+    say!("{}",
 "press up and down to navigate through command history
-all `range` arguments use tilde range syntax (X~, ~X, X~Y)
+press tab to complete commands, file names and words from the buffer
+all `range` arguments use tilde range syntax (X~, ~X, X~Y), and . means the current line
+separate commands with ; to run them one after another, like ~save; ~show
+put a count before a command to run it that many times, like ~3 indent . 4
+commands can be shortened as long as it's clear which one you mean, like ~sh for ~show
+start a line with \\~ to type text that starts with ~
+wrap arguments in \"double\" or 'single' quotes to keep their spaces
+a command sued doesn't have runs the plugin sued-command from PATH, if there is one, like ~fmt 3~8 runs sued-fmt
+Rhai scripts in the scripts folder next to the config file are commands too, like ~count runs count.rhai
 key: ~command arg1/alt_arg1 arg2 [optional_arg] - what the command does
 ~about - display about text
+~alias [name] [expansion] - list aliases, or show or define one, with $1-$9 and $@ for arguments
+~alias save/delete name - keep an alias in the config file, or get rid of it
+~around [line] [lines] - show a line with 3 lines, or however many, either side of it
+~buffer [number/next/previous] - list buffers, or switch to another one
+~buffer new [filename] - make a new buffer, optionally opening a file in it
+~buffer close - close the current buffer
 ~clear - clear buffer
+~clipboard [auto/native/osc52/internal] - show or set where ~copy copies to
 ~copy [range] - copy range or whole buffer to clipboard
 ~correct - replace most recent line (interactive)
 ~delete range - immediately delete specified range of lines
+~eval expression - evaluate a Rhai expression, which can read and change the buffer
 ~exit - exit sued
 ~help - display this list
-~indent range level - indent a range, negative level will outdent
+~history [number] - list history, or re-run an entry
+~indent range [level] - indent a range by level or the tab width, negative level will outdent
 ~insert line - insert text at specified line (interactive)
+~macro [record/run/show/save/delete] [name] - list macros, or record, replay or manage one
+~macro stop - stop recording a macro
 ~nothing - do nothing with the buffer contents
 ~open [filename] - load file into buffer
+~paste [line] - insert the last copied text at line, or at the end
 ~prefix [prefix] - set command prefix
 ~print [range] - print the contents of the buffer without line numbers
-~prompt [prompt] - set input prompt
+~prompt [prompt] - set input prompt
+~readonly [on/off] - stop the buffer being changed or saved, or allow it again
 ~reopen - load last opened file into buffer
+~repeat/. [count] - run the last command again
 ~replace line - replace specified line (interactive)
+~retab range - redo the indentation of a range with tabs, or spaces if expand_tab is on
 ~run command - run executable or shell builtin
 ~runhere command - run executable or shell builtin on file contents
 ~save [filename] - save buffer to file
-~search term - perform regex search in the whole buffer
+~script filename [args] - run a Rhai script on the buffer
+~search [-C/-A/-B lines] term - perform regex search in the whole buffer, optionally with lines of context around matches
+~set [option] [value] - list options, or show or set an option
+~setlocal [option] [value/default] - like ~set, but only for this buffer
 ~show [range] - display the contents of the buffer with line numbers
 ~substitute line pattern/replacement - perform regex substitution on the specified line
 ~swap source target - swap two lines
+~what [line] [column] - show the code points of a line's characters, or just one of them
 ~write filename - write buffer to file without storing filename".replace("~", prefix));
 }
 
@@ -110,60 +242,340 @@ key: ~command arg1/alt_arg1 arg2 [optional_arg] - what the command does
 /// Invoked with the `~about` command.
 pub fn about_sued() {
     let version = env!("CARGO_PKG_VERSION");
-    println!("this is sued, v{version}\n\
+    say!("this is sued, v{version}\n\
               sued is a vector-oriented line editor, heavily inspired by the ed editor\n\
               you can write text simply by typing, and use sued's extensive command set for editing\n\
               editor commands are prefixed with a default prefix of ~, type ~help for a full list\n\
               sued written by Arsalan \"Aeri\" Kazmi <sonicspeed848@gmail.com>");
 }
 
+/// How `save` writes a buffer out, decided by the buffer's options.
+/// With `backup`, whatever was at the file path before is kept as `file_path~`.
+/// With `crlf`, lines are ended with `\r\n` instead of `\n`.
+pub struct SaveOptions {
+    pub backup: bool,
+    pub crlf: bool,
+}
+
+/// Writes the `buffer_contents` to the `file_path`, the way `save_options` says to.
+/// Returns a message saying what went wrong if it couldn't.
+pub fn write_file(buffer_contents: &[String], file_path: &str, save_options: SaveOptions) -> Result<(), String> {
+    let content = buffer_contents.join(if save_options.crlf { "\r\n" } else { "\n" });
+    let path = PathBuf::from(file_path);
+
+    if save_options.backup && path.is_file() {
+        let backup_path = format!("{}~", file_path);
+        fs::copy(&path, &backup_path).map_err(|error| format!("couldn't back up {} to {}: {}", file_path, backup_path, error))?;
+    }
+
+    fs::write(&path, content).map_err(|error| format!("couldn't save file to {}: {}", file_path, error))
+}
+
 /// Writes the `buffer_contents` to the `file_path`, if there are any contents.
 /// Used to provide functionality for the `~save` command.
-pub fn save(buffer_contents: &Vec<String>, file_path: &str) {
+pub fn save(buffer_contents: &[String], file_path: &str, save_options: SaveOptions) {
     if buffer_contents.is_empty() {
-        println!("buffer empty - nothing to save");
+        say!("buffer empty - nothing to save");
         return;
     }
 
-    let content = buffer_contents.join("\n");
-    let path = PathBuf::from(file_path);
-
-    match fs::write(&path, content) {
-        Ok(_) => println!("saved to {}", &path.display()),
-        Err(error) => eprintln!("couldn't save file to {}: {}", file_path, error),
+    match write_file(buffer_contents, file_path, save_options) {
+        Ok(()) => say!("saved to {}", file_path),
+        Err(error) => eprintln!("{}", error),
     }
 }
 
+/// How `show` displays a buffer, decided by the buffer's options.
+/// With `colour`, the line numbers are dimmed so the text stands out.
+/// With `wrap`, lines too long for the terminal are wrapped, with the rest of the line under the text, not the line numbers.
+/// With `language` and `colour`, the text is syntax highlighted.
+/// With `list`, invisible characters are shown with markers, see `highlight::visualise`, and tabs are `tab_width` wide.
+/// `numbering` says whether line numbers count from the start of the buffer or from the `cursor`,
+/// and `gutter` is what goes between the line numbers and the text, which is a space if it's empty.
+/// `pager` is the `pager` option, see `pager::page`.
+pub struct ShowOptions {
+    pub line_numbers: bool,
+    pub numbering: Numbering,
+    pub cursor: usize,
+    pub gutter: String,
+    pub colour: bool,
+    pub wrap: bool,
+    pub language: Option<highlight::Language>,
+    pub list: bool,
+    pub tab_width: usize,
+    pub pager: String,
+}
+
+/// How `show` numbers lines, which is the `numbering` option.
+/// `Relative` numbers count up and down from the cursor, which is line 0,
+/// and `Hybrid` is the same, but with the cursor's real line number instead of 0.
+pub enum Numbering {
+    Absolute,
+    Relative,
+    Hybrid,
+}
+
 /// Iterates over the `buffer_contents` and displays them one by one.
 /// If a range was specified, only iterate for that part.
-/// Used to provide functionality for the `~show` command.
-pub fn show(buffer_contents: &Vec<String>, start_point: usize, end_point: usize, line_numbers: bool) {
+/// Long output goes through the pager, see `pager::page`.
+/// Used to provide functionality for the `~show` and `~print` commands.
+pub fn show(buffer_contents: &[String], start_point: usize, end_point: usize, show_options: &ShowOptions) {
     if buffer_contents.is_empty() {
-        println!("no buffer contents");
-        return;
+        say!("no buffer contents");
     }
     else if !check_if_line_in_buffer(buffer_contents, start_point, false) {
-        println!("invalid start point {}", start_point);
-        return;
+        say!("invalid start point {}", start_point);
     }
     else if !check_if_line_in_buffer(buffer_contents, end_point, false) {
-        println!("invalid end point {}", end_point);
-        return;
+        say!("invalid end point {}", end_point);
     }
     else {
-        let contents: Vec<String> = buffer_contents[start_point - 1..end_point].to_vec();
-        let max_count_length: usize = (start_point + contents.len() - 1).to_string().len();
-        for (index, line) in contents.iter().enumerate() {
-            if line_numbers {
-                let count: usize = start_point + index;
-                let count_padded: String = format!("{:width$}", count, width = max_count_length);
-                println!("{}│{}", count_padded, line);
+        let lines: Vec<String> = render_lines(buffer_contents, &[(start_point, end_point)], &[], show_options);
+        pager::page(&lines, &show_options.pager);
+    }
+}
+
+/// Shows `context` lines either side of `line_number`, with `line_number` marked.
+/// Provides functionality for the `~around` command.
+pub fn around(buffer_contents: &[String], line_number: usize, context: usize, show_options: &ShowOptions) {
+    if check_if_line_in_buffer(buffer_contents, line_number, true) {
+        let start_point: usize = line_number.saturating_sub(context).max(1);
+        let end_point: usize = (line_number + context).min(buffer_contents.len());
+        let lines: Vec<String> = render_lines(buffer_contents, &[(start_point, end_point)], &[line_number], show_options);
+        pager::page(&lines, &show_options.pager);
+    }
+}
+
+/// Renders the lines of `buffer_contents` in each of the `ranges` for display, the way `show_options` says to.
+/// Lines in `marked` get a `▶` after their line number instead of the gutter.
+/// Ranges have to be in order, in the buffer, and not overlapping. Ranges with a gap between them are separated with `--`, like grep does.
+fn render_lines(buffer_contents: &[String], ranges: &[(usize, usize)], marked: &[usize], show_options: &ShowOptions) -> Vec<String> {
+    let line_label = |line_number: usize| match show_options.numbering {
+        Numbering::Relative => line_number.abs_diff(show_options.cursor),
+        Numbering::Hybrid if line_number != show_options.cursor => line_number.abs_diff(show_options.cursor),
+        _ => line_number,
+    };
+    let max_label: usize = ranges.iter()
+        .flat_map(|&(start_point, end_point)| start_point..end_point + 1)
+        .map(line_label)
+        .max()
+        .unwrap_or(0);
+    let max_count_length: usize = max_label.to_string().width();
+    let separator: &str = if show_options.gutter.is_empty() { " " } else { &show_options.gutter };
+    let marked_separator: String = format!("▶{}", " ".repeat(separator.width().saturating_sub(1)));
+    let gutter_width: usize = if show_options.line_numbers { max_count_length + separator.width() } else { 0 };
+    let terminal_width: Option<usize> = if show_options.wrap { pager::terminal_width() } else { None };
+    let gutter = |count: String, mark: bool| {
+        let count_padded: String = pad_to_width(&count, max_count_length);
+        let separator: &str = if mark { &marked_separator } else { separator };
+        match (show_options.colour, mark) {
+            (true, true) => format!("\x1b[1m{}{}\x1b[0m", count_padded, separator),
+            (true, false) => format!("\x1b[2m{}{}\x1b[0m", count_padded, separator),
+            (false, _) => format!("{}{}", count_padded, separator),
+        }
+    };
+
+    let mut highlighter = show_options.language.filter(|_| show_options.colour).map(highlight::Highlighter::new);
+    let mut lines: Vec<String> = Vec::new();
+    let mut previous_end: usize = 0;
+    for &(start_point, end_point) in ranges {
+        if previous_end > 0 && start_point > previous_end + 1 {
+            lines.push(String::from(if show_options.colour { "\x1b[2m--\x1b[0m" } else { "--" }));
+        }
+        if let Some(highlighter) = highlighter.as_mut() {
+            // Comments and strings can start before the first line shown
+            for line in &buffer_contents[previous_end..start_point - 1] {
+                highlighter.highlight(line);
+            }
+        }
+        previous_end = end_point;
+
+        for line_number in start_point..=end_point {
+            let line: &str = &buffer_contents[line_number - 1];
+            let mut spans: Vec<highlight::Span> = highlighter.as_mut().map(|highlighter| highlighter.highlight(line)).unwrap_or_default();
+            let visualised: highlight::Visualised;
+            let line: &str = if show_options.list {
+                visualised = highlight::visualise(line, show_options.tab_width);
+                if show_options.colour {
+                    spans = visualised.spans(spans);
+                }
+                &visualised.text
             }
             else {
-                println!("{}", line);
+                line
+            };
+            let rows: Vec<Range<usize>> = match terminal_width {
+                Some(terminal_width) => soft_wrap(line, gutter_width, terminal_width),
+                None => std::iter::once(0..line.len()).collect(),
+            };
+            let mark: bool = marked.contains(&line_number);
+            for (row_index, row) in rows.into_iter().enumerate() {
+                let row: String = highlight::paint(line, row, &spans);
+                if !show_options.line_numbers {
+                    lines.push(row);
+                }
+                else if row_index == 0 {
+                    lines.push(format!("{}{}", gutter(line_label(line_number).to_string(), mark), row));
+                }
+                else {
+                    lines.push(format!("{}{}", gutter(String::new(), mark), row));
+                }
             }
         }
     }
+    lines
+}
+
+/// Pads `text` with spaces on the left until it takes up `width` columns on the screen.
+/// Unlike `format!`, this counts wide characters, like CJK characters and emoji, as two columns.
+pub fn pad_to_width(text: &str, width: usize) -> String {
+    format!("{}{}", " ".repeat(width.saturating_sub(text.width())), text)
+}
+
+/// Splits `line` into rows that fit in a terminal `terminal_width` columns wide, for `show`.
+/// `start_column` is where the text starts on the screen, after the line numbers.
+/// Characters are counted by how wide they're displayed, so wide characters aren't split across rows,
+/// and tabs reach the next tab stop, which is every 8 columns in most terminals.
+/// Returns the byte range of each row in `line`.
+fn soft_wrap(line: &str, start_column: usize, terminal_width: usize) -> Vec<Range<usize>> {
+    let available = terminal_width.saturating_sub(start_column).max(1);
+    let char_width = |c: char, column: usize| match c {
+        '\t' => 8 - (start_column + column) % 8,
+        c => c.width().unwrap_or(0),
+    };
+
+    let mut rows: Vec<Range<usize>> = Vec::new();
+    let mut row_start = 0;
+    let mut column = 0;
+    for (index, c) in line.char_indices() {
+        if column > 0 && column + char_width(c, column) > available {
+            rows.push(row_start..index);
+            row_start = index;
+            column = 0;
+        }
+        column += char_width(c, column);
+    }
+    rows.push(row_start..line.len());
+    rows
+}
+
+/// Returns whether sued should use colour in its output.
+/// That's only when `colour` is turned on, `NO_COLOR` isn't set, and the output is going to a terminal.
+pub fn colour_enabled(colour: bool) -> bool {
+    colour && env::var_os("NO_COLOR").is_none() && io::stdout().is_terminal() && !output::capturing()
+}
+
+/// Returns where sued looks for its config file.
+/// That's `$XDG_CONFIG_HOME/sued/config` on Linux, or the equivalent elsewhere.
+pub fn config_file_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|config_dir| config_dir.join("sued").join("config"))
+}
+
+/// Reads the TOML config file at `config_path`.
+/// A missing config file is fine (what the frick is a config file anyway?), a broken one gets reported.
+pub fn load_config(config_path: &Path) -> Option<toml::Table> {
+    let contents = fs::read_to_string(config_path).ok()?;
+    match contents.parse::<toml::Table>() {
+        Ok(config) => Some(config),
+        Err(e) => {
+            say!("couldn't read config file {}, because {}", config_path.display(), e.message().to_lowercase());
+            None
+        }
+    }
+}
+
+/// Changes `key` in the `table` of the config file at `config_path`, keeping the rest of the file as it is.
+/// Sets the `key` to `value`, or removes it if `value` is `None`.
+/// Used by `~alias` and `~macro` to keep aliases and macros for next time.
+pub fn update_config(config_path: &Path, table: &str, key: &str, value: Option<toml_edit::Value>) -> Result<(), String> {
+    let contents = match fs::read_to_string(config_path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(format!("couldn't read {}, because {}", config_path.display(), e)),
+    };
+    let mut config = contents.parse::<toml_edit::DocumentMut>()
+        .map_err(|e| format!("couldn't read {}, because {}", config_path.display(), e))?;
+
+    match value {
+        Some(value) => {
+            let config_table = config.entry(table)
+                .or_insert(toml_edit::table())
+                .as_table_mut()
+                .ok_or(format!("{} in {} isn't a table", table, config_path.display()))?;
+            config_table.insert(key, toml_edit::value(value));
+        }
+        None => match config.get_mut(table).and_then(|config_table| config_table.as_table_mut()) {
+            Some(config_table) => {
+                config_table.remove(key);
+            }
+            None => return Ok(()),
+        },
+    }
+
+    if let Some(parent) = config_path.parent() {
+        fs::create_dir_all(parent).map_err(|e| format!("couldn't create {}, because {}", parent.display(), e))?;
+    }
+    fs::write(config_path, config.to_string()).map_err(|e| format!("couldn't write {}, because {}", config_path.display(), e))
+}
+
+/// Returns where sued keeps its history between sessions.
+/// That's `$XDG_DATA_HOME/sued/history` on Linux, or the equivalent elsewhere.
+pub fn history_file_path() -> Option<PathBuf> {
+    dirs::data_dir().map(|data_dir| data_dir.join("sued").join("history"))
+}
+
+/// Reads the history left behind by previous sessions.
+/// A missing history file just means there's no history yet.
+pub fn load_history(history_path: &Path) -> Vec<String> {
+    match fs::read_to_string(history_path) {
+        Ok(contents) => contents.lines().map(String::from).collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+/// Writes the last `history_size` entries of `history` for the next session to pick up.
+/// Invoked when sued exits.
+pub fn save_history(history: &[String], history_path: &Path, history_size: usize) {
+    let start = history.len().saturating_sub(history_size);
+    let contents: String = history[start..].iter().map(|entry| format!("{}\n", entry)).collect();
+    let saved = match history_path.parent() {
+        Some(parent) => fs::create_dir_all(parent).and_then(|_| fs::write(history_path, contents)),
+        None => fs::write(history_path, contents),
+    };
+    if let Err(e) = saved {
+        eprintln!("couldn't save history to {}: {}", history_path.display(), e);
+    }
+}
+
+/// Adds an `entry` to the `history`, unless it's the same as the last one.
+/// Only the most recent `history_size` entries are kept.
+pub fn record_history(history: &mut Vec<String>, entry: &str, history_size: usize) {
+    if entry.trim().is_empty() || history.last().map(String::as_str) == Some(entry) {
+        return;
+    }
+    history.push(entry.to_string());
+    if history.len() > history_size {
+        history.drain(..history.len() - history_size);
+    }
+}
+
+/// Displays every entry in the `history` with its number.
+/// Provides functionality for the `~history` command.
+pub fn show_history(history: &[String]) {
+    if history.is_empty() {
+        say!("no history");
+        return;
+    }
+    let max_count_length: usize = history.len().to_string().len();
+    for (index, entry) in history.iter().enumerate() {
+        say!("{:width$}│{}", index + 1, entry, width = max_count_length);
+    }
+}
+
+/// Checks whether the file at `file_path` uses Windows line endings.
+/// Used so that `~save` can keep the line endings the file came with.
+pub fn has_crlf_line_endings(file_path: &str) -> bool {
+    fs::read(file_path).is_ok_and(|contents| contents.windows(2).any(|pair| pair == b"\r\n"))
 }
 
 /// Verifies the `file_path`'s file existence, then returns the file contents as a `String` vector.
@@ -175,35 +587,26 @@ pub fn open(file_path: &str, current_file_path: &mut Option<String>) -> Vec<Stri
     let is_dir = path_exists && fs::metadata(file_path).unwrap().is_dir();
     match file_exists {
         Ok(contents) => {
-            println!("file {} opened", file_path);
+            say!("file {} opened", file_path);
             *current_file_path = Some(file_path.to_string());
             return contents.lines().map(|line| line.to_owned()).collect();
         }
         Err(e) => {
             if is_dir {
-                println!("{} is a directory", file_path);
+                say!("{} is a directory", file_path);
                 let listings: Vec<String> = fs::read_dir(file_path).unwrap().map(
                     |res| res.unwrap().path().display().to_string()
                 ).collect();
-                println!("directory listing of {} opened as text", file_path);
+                say!("directory listing of {} opened as text", file_path);
                 return listings;
             }
-            let error_specifier: &str;
-            match e.kind() {
-                std::io::ErrorKind::NotFound => {
-                    error_specifier = "not found";
-                },
-                std::io::ErrorKind::PermissionDenied => {
-                    error_specifier = "can't be opened";
-                },
-                std::io::ErrorKind::InvalidData => {
-                    error_specifier = "is not text";
-                }
-                _ => {
-                    error_specifier = "failed to open";
-                }
-            }
-            println!("file {} {}: {}", file_path, error_specifier, e.to_string());
+            let error_specifier: &str = match e.kind() {
+                std::io::ErrorKind::NotFound => "not found",
+                std::io::ErrorKind::PermissionDenied => "can't be opened",
+                std::io::ErrorKind::InvalidData => "is not text",
+                _ => "failed to open",
+            };
+            say!("file {} {}: {}", file_path, error_specifier, e);
         }
     }
     Vec::new()
@@ -211,17 +614,17 @@ pub fn open(file_path: &str, current_file_path: &mut Option<String>) -> Vec<Stri
 
 /// Checks if a given `line_number` is in the `file_buffer`.
 /// Used by `insert`, `replace`, `swap` and `delete`.
-fn check_if_line_in_buffer(file_buffer: &Vec<String>, line_number: usize, verbose: bool) -> bool {
+fn check_if_line_in_buffer(file_buffer: &[String], line_number: usize, verbose: bool) -> bool {
     if line_number < 1 {
         if verbose {
-            println!("invalid line {}", line_number);
+            say!("invalid line {}", line_number);
         }
         return false;
     }
 
     if file_buffer.is_empty() {
         if verbose {
-            println!("no buffer contents");
+            say!("no buffer contents");
         }
         return false;
     }
@@ -231,7 +634,7 @@ fn check_if_line_in_buffer(file_buffer: &Vec<String>, line_number: usize, verbos
     }
 
     if verbose {
-        println!("no line {}", line_number);
+        say!("no line {}", line_number);
     }
 
     false
@@ -241,7 +644,7 @@ fn check_if_line_in_buffer(file_buffer: &Vec<String>, line_number: usize, verbos
 /// Provides functionality for the `~insert` command.
 pub fn insert(file_buffer: &mut Vec<String>, line_number: usize) {
     if check_if_line_in_buffer(file_buffer, line_number, true) {
-        println!("inserting into line {}", line_number);
+        say!("inserting into line {}", line_number);
 
         let mut input = String::new();
         io::stdin().read_line(&mut input).expect("Failed to read input.");
@@ -249,11 +652,11 @@ pub fn insert(file_buffer: &mut Vec<String>, line_number: usize) {
         let index = line_number - 1;
         if !input.trim().is_empty() {
             file_buffer.insert(index, input.trim_end_matches('\n').to_string());
-            println!("inserted");
+            say!("inserted");
         }
         else {
             file_buffer.insert(index, String::new());
-            println!("inserted newline");
+            say!("inserted newline");
         }
     }
 }
@@ -280,12 +683,12 @@ pub fn replace(file_buffer: &mut Vec<String>, line_number: usize) {
         let trimmed_line = original_line.trim();
         let leading_spaces = count_leading_spaces(&original_line);
 
-        println!("replacing line {}", line_number);
+        say!("replacing line {}", line_number);
 
         match leading_spaces {
-            n if n >= 2 => println!("original line is '{}' (indented by {} spaces)", trimmed_line, n),
-            1 => println!("original line is '{}' (indented by 1 space)", trimmed_line),
-            _ => println!("original line is '{}'", trimmed_line),
+            n if n >= 2 => say!("original line is '{}' (indented by {} spaces)", trimmed_line, n),
+            1 => say!("original line is '{}' (indented by 1 space)", trimmed_line),
+            _ => say!("original line is '{}'", trimmed_line),
         }
 
         let mut input = String::new();
@@ -295,20 +698,29 @@ pub fn replace(file_buffer: &mut Vec<String>, line_number: usize) {
         if !input.trim().is_empty() {
             file_buffer.insert(index, input.trim_end_matches('\n').to_string());
             file_buffer.remove(index + 1);
-            println!("replaced");
+            say!("replaced");
         }
         else {
-            println!("replace cancelled; try ~delete if you wanted that instead");
+            say!("replace cancelled; try ~delete if you wanted that instead");
         }
     }
 }
 
+/// Asks the user a yes/no `question`, defaulting to no.
+/// Used for commands that want to double-check before doing something.
+pub fn confirm(question: &str) -> bool {
+    say!("{} (y/N)", question);
+    let mut input = String::new();
+    io::stdin().read_line(&mut input).expect("Failed to read input.");
+    matches!(input.trim().to_lowercase().as_str(), "y" | "yes")
+}
+
 /// Swap the `source_line` with the `target_line` in the `file_buffer`.
 /// Provides functionality for the `~swap` command.
 pub fn swap(file_buffer: &mut Vec<String>, source_line: usize, target_line: usize) {
     if check_if_line_in_buffer(file_buffer, source_line, true) && check_if_line_in_buffer(file_buffer, target_line, true) {
         if source_line == target_line {
-            println!("lines are the same");
+            say!("lines are the same");
             return;
         }
 
@@ -328,48 +740,184 @@ pub fn delete(file_buffer: &mut Vec<String>, line_number: usize) {
     }
 }
 
-/// Copy the provided `line_number` to the system clipboard.
-/// If no `line_number` is specified (it's not in the buffer), copy the whole buffer.
+/// The ways `~copy` can get text onto a clipboard.
+/// `Auto` tries the system clipboard and falls back to OSC 52 when there isn't one.
+#[derive(Clone, Copy, PartialEq)]
+pub enum ClipboardMode {
+    Auto,
+    Native,
+    Osc52,
+    Internal,
+}
+
+impl ClipboardMode {
+    /// Parses a clipboard mode name as given to the `~clipboard` command.
+    pub fn from_name(name: &str) -> Option<ClipboardMode> {
+        match name.to_lowercase().as_str() {
+            "auto" => Some(ClipboardMode::Auto),
+            "native" | "system" => Some(ClipboardMode::Native),
+            "osc52" | "osc" | "terminal" => Some(ClipboardMode::Osc52),
+            "internal" | "none" => Some(ClipboardMode::Internal),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ClipboardMode::Auto => "auto",
+            ClipboardMode::Native => "native",
+            ClipboardMode::Osc52 => "osc52",
+            ClipboardMode::Internal => "internal",
+        }
+    }
+}
+
+/// sued's own clipboard.
+/// `register` always holds the most recent copy, whatever the clipboard mode is,
+/// so `~paste` works even when there's no system clipboard to talk to.
+pub struct Clipboard {
+    pub register: Vec<String>,
+}
+
+impl Clipboard {
+    pub fn new() -> Clipboard {
+        Clipboard {
+            register: Vec::new(),
+        }
+    }
+}
+
+/// Copy the provided `range` to the clipboard chosen by `mode`.
+/// If the `range` isn't in the buffer, copy the whole buffer.
 /// Provides functionality for the `~copy` command.
-pub fn copy(file_buffer: &mut Vec<String>, range: (usize, usize)) {
+pub fn copy(file_buffer: &[String], range: (usize, usize), clipboard: &mut Clipboard, mode: ClipboardMode) {
     if file_buffer.is_empty() {
-        println!("no buffer contents");
+        say!("no buffer contents");
         return;
     }
-    #[cfg(any(target_os = "android", target_os = "ios"))] {
-        println!("~copy is unsupported on your device, sorry");
+
+    let mut to_copy: &[String] = file_buffer;
+    let mut copy_message = String::from("copying whole buffer");
+    if range.0 == range.1 {
+        let line_number = range.0;
+        if check_if_line_in_buffer(file_buffer, line_number, false) {
+            to_copy = &file_buffer[line_number - 1..line_number];
+            copy_message = format!("copying line {}", line_number);
+        }
+    }
+    else if check_if_line_in_buffer(file_buffer, range.0, false) && check_if_line_in_buffer(file_buffer, range.1, false) && range.0 < range.1 {
+        to_copy = &file_buffer[range.0 - 1..range.1];
+        copy_message = format!("copying lines {} to {}", range.0, range.1);
+    }
+    say!("{}", copy_message);
+
+    clipboard.register = to_copy.to_vec();
+    let text = to_copy.join("\n");
+
+    match mode {
+        ClipboardMode::Internal => say!("copied to sued's clipboard"),
+        ClipboardMode::Native => {
+            if let Err(e) = copy_native(&text) {
+                say!("copy failed, because {}", e);
+            }
+        }
+        ClipboardMode::Osc52 => copy_osc52(&text),
+        ClipboardMode::Auto => {
+            let remote = env::var_os("SSH_TTY").is_some() || env::var_os("SSH_CONNECTION").is_some();
+            if remote || copy_native(&text).is_err() {
+                copy_osc52(&text);
+            }
+        }
+    }
+}
+
+/// Puts `text` on the system clipboard through copypasta.
+/// Checks for a display first on X11/Wayland systems, since copypasta isn't very graceful without one.
+fn copy_native(text: &str) -> Result<(), String> {
+    if cfg!(any(target_os = "android", target_os = "ios")) {
+        return Err(String::from("the system clipboard is unsupported on your device"));
+    }
+    if cfg!(all(unix, not(target_os = "macos"))) && env::var_os("DISPLAY").is_none() && env::var_os("WAYLAND_DISPLAY").is_none() {
+        return Err(String::from("there's no display to copy to"));
+    }
+    let mut clipboard_context = ClipboardContext::new().map_err(|e| e.to_string())?;
+    clipboard_context.set_contents(text.to_string()).map_err(|e| e.to_string())
+}
+
+/// Puts `text` on the terminal's clipboard with the OSC 52 escape sequence.
+/// This works over SSH, as long as the terminal on the other end supports it.
+/// Inside tmux, the sequence is wrapped so tmux passes it through to the outer terminal.
+/// While output is being captured, stdout isn't the terminal, so the sequence goes to `/dev/tty` instead,
+/// and if there isn't one, the text is only on sued's clipboard.
+fn copy_osc52(text: &str) {
+    let sequence = format!("\x1b]52;c;{}\x07", base64_encode(text.as_bytes()));
+    let sequence = if env::var_os("TMUX").is_some() {
+        format!("\x1bPtmux;{}\x1b\\", sequence.replace('\x1b', "\x1b\x1b"))
+    }
+    else {
+        sequence
+    };
+
+    if output::capturing() {
+        let written = fs::OpenOptions::new().write(true).open("/dev/tty")
+            .and_then(|mut terminal| terminal.write_all(sequence.as_bytes()).and_then(|_| terminal.flush()));
+        if written.is_err() {
+            say!("there's no terminal to copy to, so it's only on sued's clipboard");
+        }
         return;
     }
-    let mut clipboard_context = ClipboardContext::new().unwrap();
-    let file_contents = file_buffer.join("\n");
-    let mut to_copy = file_contents;
-
-    match clipboard_context.get_contents() {
-        Ok(_) => {
-            let mut copy_message = String::new();
-            if range.0 == range.1 {
-                let line_number = range.0;
-                if check_if_line_in_buffer(file_buffer, line_number, false) {
-                    to_copy = file_buffer[line_number - 1].clone();
-                    copy_message = format!("copying line {}", line_number);
-                }
+
+    let mut stdout = io::stdout();
+    if stdout.write_all(sequence.as_bytes()).and_then(|_| stdout.flush()).is_err() {
+        say!("copy failed, because the terminal couldn't be written to");
+    }
+}
+
+/// A helper function for OSC 52 copying.
+/// Returns `input` encoded as standard, padded base64.
+fn base64_encode(input: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity(input.len().div_ceil(3) * 4);
+    for chunk in input.chunks(3) {
+        let bytes = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let triple = ((bytes[0] as u32) << 16) | ((bytes[1] as u32) << 8) | bytes[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(triple >> (18 - i * 6)) as usize & 0x3F] as char);
             }
             else {
-                if check_if_line_in_buffer(file_buffer, range.0, false) && check_if_line_in_buffer(file_buffer, range.1, false) {
-                    to_copy = file_buffer[range.0 - 1..range.1].join("\n");
-                    copy_message = format!("copying lines {} to {}", range.0, range.1);
-                }
+                encoded.push('=');
             }
-            println!("{}", copy_message);
-            clipboard_context.set_contents(to_copy).unwrap();
         }
-        Err(e) => println!("copy failed, because {}", e),
+    }
+    encoded
+}
+
+/// Insert the contents of sued's clipboard at `line_number`, or at the end if there's no `line_number`.
+/// Provides functionality for the `~paste` command.
+pub fn paste(file_buffer: &mut Vec<String>, line_number: Option<usize>, clipboard: &Clipboard) {
+    if clipboard.register.is_empty() {
+        say!("nothing to paste; try ~copy first");
+        return;
+    }
+    match line_number {
+        Some(line_number) => {
+            if check_if_line_in_buffer(file_buffer, line_number, true) {
+                let index = line_number - 1;
+                file_buffer.splice(index..index, clipboard.register.iter().cloned());
+                say!("pasted into line {}", line_number);
+            }
+        }
+        None => {
+            file_buffer.extend(clipboard.register.iter().cloned());
+            say!("pasted");
+        }
     }
 }
 
 /// Perform a regex `replace()` on `line_number`, with the `pattern` and `replacement`.
 /// Provides functionality for the `~substitute` command.
-pub fn substitute(file_buffer: &mut Vec<String>, line_number: usize, pattern: &str, replacement: &str) {
+pub fn substitute(file_buffer: &mut [String], line_number: usize, pattern: &str, replacement: &str) {
     if check_if_line_in_buffer(file_buffer, line_number, true) {
         let index = line_number - 1;
         let line = &mut file_buffer[index];
@@ -383,10 +931,10 @@ pub fn substitute(file_buffer: &mut Vec<String>, line_number: usize, pattern: &s
                 let lines: Vec<String> = error_message.lines().map(String::from).collect();
 
                 if let Some(error) = lines.last() {
-                    println!("substitute failed, because {}", error.to_lowercase().replace("error: ", ""));
+                    say!("substitute failed, because {}", error.to_lowercase().replace("error: ", ""));
                 }
                 else {
-                    println!("substitute failed, for some reason");
+                    say!("substitute failed, for some reason");
                 }
             },
         }
@@ -394,79 +942,194 @@ pub fn substitute(file_buffer: &mut Vec<String>, line_number: usize, pattern: &s
 }
 
 /// Searches for the given `term` in the `file_buffer` and prints matching lines.
+/// With `context`, which is how many lines to show before and after each match, matches are shown the way `~show` shows them instead,
+/// with the matching lines marked.
 /// Provides functionality for the `~search` command.
-pub fn search(file_buffer: &[String], term: &str) {
+pub fn search(file_buffer: &[String], term: &str, context: Option<(usize, usize)>, show_options: &ShowOptions) {
     let escaped_term = regex::escape(term);
 
     let regex = Regex::new(escaped_term.as_str()).unwrap();
+    let matches: Vec<usize> = file_buffer.iter()
+        .enumerate()
+        .filter(|(_, line)| regex.is_match(line))
+        .map(|(index, _)| index + 1)
+        .collect();
 
-    for (line_number, line) in file_buffer.iter().enumerate() {
-        if regex.is_match(line) {
-            println!("line {}: {}", line_number + 1, line);
+    let Some((before, after)) = context else {
+        for &line_number in &matches {
+            say!("line {}: {}", line_number, file_buffer[line_number - 1]);
+        }
+        return;
+    };
+
+    // Matches close enough together share their context
+    let mut ranges: Vec<(usize, usize)> = Vec::new();
+    for &line_number in &matches {
+        let start_point: usize = line_number.saturating_sub(before).max(1);
+        let end_point: usize = (line_number + after).min(file_buffer.len());
+        match ranges.last_mut() {
+            Some(last) if start_point <= last.1 + 1 => last.1 = end_point,
+            _ => ranges.push((start_point, end_point)),
         }
     }
+    let lines: Vec<String> = render_lines(file_buffer, &ranges, &matches, show_options);
+    pager::page(&lines, &show_options.pager);
 }
 
-/// Run a shell command with the OS shell, and fall back to a shell built-in if it fails.
-/// Provides functionality for the `~run` command.
-pub fn shell_command(mut command_args: Vec<&str>) {
-    if command_args.len() <= 1 {
-        println!("run what?");
-    }
-    else {
-        let shell = if cfg!(windows) { 
-            if which("pwsh").is_ok() {
-                "pwsh"
-            }
-            else {
-                "powershell"
+/// A helper function for the `~search` command.
+/// Reads grep-style context options from the start of `command_args`: `-C n` for n lines either side of a match,
+/// and `-B n` and `-A n` for n lines before and after. `--` ends the options, so a search can start with `-`.
+/// Returns how many lines to show before and after each match, if any options were given, and where the search term starts.
+pub fn parse_search_context(command_args: &[&str]) -> Result<(Option<(usize, usize)>, usize), String> {
+    let mut context: Option<(usize, usize)> = None;
+    let mut index = 0;
+    while let Some(&arg) = command_args.get(index) {
+        if arg == "--" {
+            index += 1;
+            break;
+        }
+        let Some(flag @ ('A' | 'B' | 'C')) = arg.strip_prefix('-').and_then(|flag| flag.chars().next()) else {
+            break;
+        };
+        // The number can be part of the option, like -C3, or the next argument, like -C 3.
+        // Anything else stuck to the option, like -Beta, isn't an option at all, so the search starts there.
+        let (lines, used) = match &arg[2..] {
+            "" => {
+                let number = command_args.get(index + 1).copied().unwrap_or_default();
+                match number.parse() {
+                    Ok(lines) => (lines, 2),
+                    Err(_) if number.is_empty() => return Err(format!("-{} needs a number of lines", flag)),
+                    Err(_) => return Err(format!("-{} needs a number of lines, not {}", flag, number)),
+                }
             }
+            number => match number.parse() {
+                Ok(lines) => (lines, 1),
+                Err(_) => break,
+            },
+        };
+        let (before, after) = context.get_or_insert((0, 0));
+        match flag {
+            'A' => *after = lines,
+            'B' => *before = lines,
+            _ => (*before, *after) = (lines, lines),
         }
-        else { 
-            "sh" 
+        index += used;
+    }
+    Ok((context, index))
+}
+
+/// Shows the code point of every character on `line_number`, or just the one at `column`,
+/// so characters that look alike, or don't look like anything, can be told apart.
+/// Columns count characters, not bytes or screen columns.
+/// Provides functionality for the `~what` command.
+pub fn what(file_buffer: &[String], line_number: usize, column: Option<usize>) {
+    if !check_if_line_in_buffer(file_buffer, line_number, true) {
+        return;
+    }
+    let line = &file_buffer[line_number - 1];
+    let describe = |column: usize, c: char| {
+        let shown = match highlight::describe_char(c) {
+            Some(_) => highlight::visualise(&c.to_string(), 1).text,
+            None => c.to_string(),
         };
+        let description = highlight::describe_char(c).map(|description| format!(", {}", description)).unwrap_or_default();
+        let bytes = if c.len_utf8() == 1 { "byte" } else { "bytes" };
+        format!("column {}: {} is U+{:04X}, {} {}{}", column, shown, c as u32, c.len_utf8(), bytes, description)
+    };
+
+    match column {
+        Some(column) => match line.chars().nth(column.wrapping_sub(1)) {
+            Some(c) => say!("{}", describe(column, c)),
+            None => say!("line {} doesn't have a column {}, it's {} characters long", line_number, column, line.chars().count()),
+        },
+        None if line.is_empty() => say!("line {} is empty", line_number),
+        None => {
+            for (index, c) in line.chars().enumerate() {
+                say!("{}", describe(index + 1, c));
+            }
+        }
+    }
+}
 
+/// Run a shell command with the OS shell, and fall back to a shell built-in if it fails.
+/// The `command_line` is passed to the shell untouched, so the shell's own quoting rules apply.
+/// Provides functionality for the `~run` command.
+pub fn shell_command(command_line: &str) {
+    let command_line = command_line.trim();
+    if command_line.is_empty() {
+        say!("run what?");
+    }
+    else {
+        let shell = shell();
         let arg = "-c";
-        let command = command_args[1];
+        let command = command_line.split_whitespace().next().unwrap_or_default();
 
-        if command == "sued" {
+        if command == "sued" && !output::capturing() {
             editor_overflow();
             return;
         }
 
         match which(command) {
-            Ok(path) => println!("running {}", path.to_string_lossy()),
-            Err(_) => println!("{} wasn't found; trying to run it anyway", &command)
+            Ok(path) => say!("running {}", path.to_string_lossy()),
+            Err(_) => say!("{} wasn't found; trying to run it anyway", &command)
         }
 
-        command_args.drain(0..1);
+        // Captured output isn't going to a terminal, so the command can't write to one or read from it either
+        let status = if output::capturing() {
+            let command_output = Command::new(shell)
+                .arg(arg)
+                .arg(command_line)
+                .stdin(Stdio::null())
+                .output()
+                .expect("command failed");
+            for line in String::from_utf8_lossy(&command_output.stdout).lines().chain(String::from_utf8_lossy(&command_output.stderr).lines()) {
+                say!("{}", line);
+            }
+            command_output.status
+        }
+        else {
+            Command::new(shell)
+                .arg(arg)
+                .arg(command_line)
+                .status()
+                .expect("command failed")
+        };
 
-        let cmd = Command::new(shell)
-            .arg(arg)
-            .arg(command_args.join(" "))
-            .status()
-            .expect("command failed");
+        if status.success() {
+            say!("finished running {}", &command);
+        }
+        else {
+            say!("finished running {} with errors", &command);
+        }
+    }
+}
 
-        if cmd.success() {
-            println!("finished running {}", &command);
+/// Returns the shell sued runs commands with, which is `sh`, or PowerShell on Windows.
+/// Both take the command to run after `-c`.
+pub fn shell() -> &'static str {
+    if cfg!(windows) {
+        if which("pwsh").is_ok() {
+            "pwsh"
         }
         else {
-            println!("finished running {} with errors", &command);
+            "powershell"
         }
     }
+    else {
+        "sh"
+    }
 }
 
 /// Passes the current `buffer_contents` to `shell_command`.
 /// Provides functionality for the `~runhere` command.
-pub fn shell_command_with_file(mut command_args: Vec<String>, buffer_contents: &mut Vec<String>, file_name: Option<String>) {
+pub fn shell_command_with_file(command_line: &str, buffer_contents: &mut Vec<String>, file_name: Option<String>) {
     if buffer_contents.is_empty() {
-        println!("no buffer contents");
+        say!("no buffer contents");
     }
     else {
-        let temporary_file_name: String = if file_name.is_some() {
-            let file = file_name.unwrap();
-            if file.contains(".") {
-                format!("{}", file.replace(".", "-temp."))
+        let temporary_file_name: String = if let Some(file) = file_name {
+            if file.contains('.') {
+                file.replace('.', "-temp.")
             }
             else {
                 format!("{}.temp", file)
@@ -484,19 +1147,17 @@ pub fn shell_command_with_file(mut command_args: Vec<String>, buffer_contents: &
             format!("{}.temp", hex_string)
         };
 
-        if command_args.len() <= 1 {
-            println!("run what?");
+        if command_line.trim().is_empty() {
+            say!("run what?");
             return;
         }
 
         if fs::write(&temporary_file_name, buffer_contents.join("\n")).is_err() {
-            println!("couldn't write temporary file");
+            say!("couldn't write temporary file");
             return;
         }
 
-        command_args.push(temporary_file_name.clone());
-
-        shell_command(command_args.iter().map(|s| s.as_str()).collect());
+        shell_command(format!("{} {}", command_line.trim(), temporary_file_name).as_str());
 
         if let Ok(new_contents) = fs::read_to_string(&temporary_file_name) {
             *buffer_contents = new_contents.lines().map(String::from).collect();
@@ -506,25 +1167,246 @@ pub fn shell_command_with_file(mut command_args: Vec<String>, buffer_contents: &
     }
 }
 
-/// Indent the line at `line_number` by `indentation` spaces.
+/// Checks that `file_path` is inside `root`, for restricted mode.
+/// `root` has to be canonical already. Paths with `..` in them are refused outright, and symlinks are followed,
+/// so neither can be used to get out of `root`. The file doesn't have to exist yet, but its directory does,
+/// and if the file is a symlink, so does whatever it points to.
+pub fn confine_path(file_path: &str, root: &Path) -> Result<PathBuf, String> {
+    let path = Path::new(file_path);
+    if path.components().any(|component| component == Component::ParentDir) {
+        return Err(format!("{} goes up a directory, which isn't allowed in restricted mode", file_path));
+    }
+    let path = root.join(path);
+    let (Some(directory), Some(file_name)) = (path.parent(), path.file_name()) else {
+        return Err(format!("{} isn't a file", file_path));
+    };
+    let directory = directory.canonicalize()
+        .map_err(|e| format!("couldn't find the directory for {}, because {}", file_path, e))?;
+    let resolved = directory.join(file_name);
+    let is_symlink = fs::symlink_metadata(&resolved).is_ok_and(|metadata| metadata.file_type().is_symlink());
+    let resolved = match resolved.canonicalize() {
+        Ok(resolved) => resolved,
+        // Writing to a symlink that doesn't lead anywhere yet creates its target, wherever that is
+        Err(_) if is_symlink => {
+            return Err(format!("{} is a symlink to something that doesn't exist, which isn't allowed in restricted mode", file_path));
+        }
+        Err(_) => resolved,
+    };
+    if !resolved.starts_with(root) {
+        return Err(format!("{} is outside {}, which isn't allowed in restricted mode", file_path, root.display()));
+    }
+    Ok(resolved)
+}
+
+/// Returns the plugin for the command `name`, which is an executable called `sued-name` on the PATH.
+/// Plugin names follow the same rules as alias names, so nothing outside the PATH can be run this way.
+pub fn find_plugin(name: &str) -> Option<PathBuf> {
+    if name.is_empty() || !name.chars().all(|c| c.is_alphanumeric() || c == '-' || c == '_') {
+        return None;
+    }
+    which(format!("sued-{}", name)).ok()
+}
+
+/// Returns the command names of every plugin on the PATH, for `~` and tab completion.
+pub fn plugin_names() -> Vec<String> {
+    let Some(path) = env::var_os("PATH") else {
+        return Vec::new();
+    };
+    let mut names: Vec<String> = env::split_paths(&path)
+        .filter_map(|directory| fs::read_dir(directory).ok())
+        .flatten()
+        .filter_map(|entry| {
+            let path = entry.ok()?.path();
+            // Windows executables are found without their extension, so leave it off the name
+            let file_name = if cfg!(windows) { path.file_stem()? } else { path.file_name()? };
+            let name = file_name.to_str()?.strip_prefix("sued-")?.to_string();
+            find_plugin(&name).map(|_| name)
+        })
+        .collect();
+    names.sort();
+    names.dedup();
+    names
+}
+
+/// What sued does with a plugin's output.
+/// The plugin picks by starting its output with `#sued:replace`, `#sued:insert` or `#sued:print`.
+pub enum PluginMode {
+    /// Replace the lines the plugin was given.
+    Replace,
+    /// Insert the output after the lines the plugin was given.
+    Insert,
+    /// Just print the output, which is what happens if the plugin doesn't say.
+    Print,
+}
+
+/// Runs the plugin at `plugin_path` with `arguments`, passing it `lines` on stdin and `environment` as environment variables.
+/// Returns the mode the plugin asked for and the rest of its output,
+/// or a message saying what went wrong if it couldn't run or didn't exit successfully.
+/// Used for commands that sued doesn't have, which are passed to plugins instead.
+pub fn run_plugin(plugin_path: &Path, arguments: &[&str], lines: &[String], environment: &[(&str, String)]) -> Result<(PluginMode, Vec<String>), String> {
+    let plugin_name = plugin_path.file_name().unwrap_or_default().to_string_lossy().to_string();
+    let mut child = Command::new(plugin_path)
+        .args(arguments)
+        .envs(environment.iter().map(|(key, value)| (key, value)))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("couldn't run {}, because {}", plugin_name, e))?;
+
+    // Writing happens on its own thread, so a plugin that prints before it's read everything can't get stuck
+    let mut stdin = child.stdin.take().expect("plugin stdin should be piped");
+    let input: String = lines.iter().map(|line| format!("{}\n", line)).collect();
+    let writer = thread::spawn(move || stdin.write_all(input.as_bytes()));
+
+    let output = child.wait_with_output().map_err(|e| format!("couldn't run {}, because {}", plugin_name, e))?;
+    writer.join().unwrap_or(Ok(())).unwrap_or_default();
+    if !output.status.success() {
+        return Err(format!("{} failed with {}", plugin_name, output.status));
+    }
+
+    let output = String::from_utf8_lossy(&output.stdout);
+    let mut output_lines: Vec<String> = output.lines().map(String::from).collect();
+    let mode = match output_lines.first().and_then(|line| line.trim().strip_prefix("#sued:")) {
+        Some(mode) => {
+            let mode = match mode {
+                "replace" => PluginMode::Replace,
+                "insert" => PluginMode::Insert,
+                "print" => PluginMode::Print,
+                _ => return Err(format!("{} asked for {}, which isn't replace, insert or print", plugin_name, mode)),
+            };
+            output_lines.remove(0);
+            mode
+        }
+        None => PluginMode::Print,
+    };
+
+    Ok((mode, output_lines))
+}
+
+/// Indent the line at `line_number` by `indentation` columns, or outdent it if `indentation` is negative.
+/// Indenting puts new whitespace in front of what's there, in tabs and then spaces, or just spaces with `expand_tab`.
+/// Outdenting only ever takes whitespace off the start of the line, so it stops at the text.
+/// Either way, existing tabs and spaces are left as they are, since that's what `~retab` is for.
 /// Used for the `~indent` command.
-pub fn indent(file_buffer: &mut Vec<String>, line_number: usize, indentation: isize) {
+pub fn indent(file_buffer: &mut [String], line_number: usize, indentation: isize, tab_width: usize, expand_tab: bool) {
+    if indentation == 0 {
+        say!("invalid indent level");
+        return;
+    }
     if check_if_line_in_buffer(file_buffer, line_number, true) {
-        let index = line_number - 1;
-        let line = &mut file_buffer[index];
-        match indentation.cmp(&0) {
-            Ordering::Greater => {
-                let indented_line = format!("{:indent$}{}", "", line, indent = indentation as usize);
-                *line = indented_line;
-            }
-            Ordering::Less => {
-                let line_len = line.len() as isize;
-                let new_len = (line_len + indentation).max(0) as usize;
-                let indented_line = format!("{:indent$}", &line[line_len as usize - new_len..], indent = new_len);
-                *line = indented_line;
+        let line = &mut file_buffer[line_number - 1];
+        *line = if indentation > 0 {
+            format!("{}{}", make_indentation(indentation as usize, tab_width, expand_tab), line)
+        }
+        else {
+            let (leading, text) = split_indentation(line);
+            format!("{}{}", remove_indentation(leading, indentation.unsigned_abs(), tab_width), text)
+        };
+    }
+}
+
+/// Rewrite the indentation of the line at `line_number` with tabs, or with spaces if `expand_tab` is on,
+/// keeping it just as wide. Only the indentation is touched, so tabs after the start of the text stay as they are.
+/// Used for the `~retab` command.
+pub fn retab(file_buffer: &mut [String], line_number: usize, tab_width: usize, expand_tab: bool) {
+    if check_if_line_in_buffer(file_buffer, line_number, true) {
+        let line = &mut file_buffer[line_number - 1];
+        let (leading, text) = split_indentation(line);
+        let columns = indentation_width(leading, tab_width);
+        *line = format!("{}{}", make_indentation(columns, tab_width, expand_tab), text);
+    }
+}
+
+/// Splits `line` into its leading spaces and tabs, and everything after them.
+fn split_indentation(line: &str) -> (&str, &str) {
+    let text_start = line.find(|c: char| c != ' ' && c != '\t').unwrap_or(line.len());
+    line.split_at(text_start)
+}
+
+/// Returns how many columns wide the `leading` whitespace is, with tabs going to the next multiple of `tab_width`.
+fn indentation_width(leading: &str, tab_width: usize) -> usize {
+    let tab_width = tab_width.max(1);
+    leading.chars().fold(0, |columns, c| match c {
+        '\t' => columns + tab_width - columns % tab_width,
+        _ => columns + 1,
+    })
+}
+
+/// Returns the `leading` whitespace with up to `columns` taken off the start of it.
+/// A tab is only taken off if all of it fits in what's left to take off.
+fn remove_indentation(leading: &str, columns: usize, tab_width: usize) -> &str {
+    let tab_width = tab_width.max(1);
+    let mut removed = 0;
+    for (i, c) in leading.char_indices() {
+        let width = if c == '\t' { tab_width - removed % tab_width } else { 1 };
+        if removed + width > columns {
+            return &leading[i..];
+        }
+        removed += width;
+    }
+    ""
+}
+
+/// Returns `columns` of indentation, in tabs and then spaces, or just spaces with `expand_tab`.
+fn make_indentation(columns: usize, tab_width: usize, expand_tab: bool) -> String {
+    if expand_tab || tab_width == 0 {
+        " ".repeat(columns)
+    }
+    else {
+        format!("{}{}", "\t".repeat(columns / tab_width), " ".repeat(columns % tab_width))
+    }
+}
+
+/// Returns the indentation a line typed after `previous_line` starts with, when the `auto_indent` option is on.
+/// That's `previous_line`'s own indentation, with a level added after it if it opens a block in the `language`,
+/// like a `{` at the end of the line, or a `:` in Python.
+pub fn next_indentation(previous_line: &str, language: Option<highlight::Language>, tab_width: usize, expand_tab: bool) -> String {
+    let (leading, text) = split_indentation(previous_line);
+    if opens_block(text.trim_end(), language) {
+        format!("{}{}", leading, make_indentation(tab_width, tab_width, expand_tab))
+    }
+    else {
+        leading.to_string()
+    }
+}
+
+/// Returns the typed `line`, which started with the `indentation` from `next_indentation`,
+/// a level less indented if it closes a block in the `language`, like a `}` or `fi`.
+/// Lines where the indentation was changed by hand are left as they are.
+pub fn settle_indentation(line: &str, indentation: &str, language: Option<highlight::Language>, tab_width: usize) -> String {
+    match line.strip_prefix(indentation) {
+        Some(text) if !text.starts_with([' ', '\t']) && closes_block(text, language) => {
+            // The level comes off the end, which is where next_indentation put it
+            let columns = indentation_width(indentation, tab_width).saturating_sub(tab_width);
+            let mut kept = indentation;
+            while indentation_width(kept, tab_width) > columns {
+                kept = &kept[..kept.len() - 1];
             }
-            _ => println!("invalid indent level"),
+            format!("{}{}", kept, text)
         }
+        _ => line.to_string(),
+    }
+}
+
+/// Returns whether a line ending with `text` opens a block in the `language`, so the next line goes in a level.
+/// Anything that isn't a language sued knows goes by brackets, and Markdown never opens blocks.
+fn opens_block(text: &str, language: Option<highlight::Language>) -> bool {
+    let last_word = text.rsplit([' ', '\t', ';']).next().unwrap_or_default();
+    match language {
+        Some(highlight::Language::Markdown) => false,
+        Some(highlight::Language::Python) => text.ends_with([':', '{', '[', '(']),
+        Some(highlight::Language::Shell) => text.ends_with(['{', '(']) || matches!(last_word, "then" | "do" | "else"),
+        _ => text.ends_with(['{', '[', '(']),
+    }
+}
+
+/// Returns whether a line starting with `text` closes a block in the `language`, so it goes out a level.
+fn closes_block(text: &str, language: Option<highlight::Language>) -> bool {
+    let first_word = text.split([' ', '\t', ';']).next().unwrap_or_default();
+    match language {
+        Some(highlight::Language::Markdown) => false,
+        Some(highlight::Language::Shell) => text.starts_with(['}', ')']) || matches!(first_word, "fi" | "done" | "else" | "elif" | "esac"),
+        _ => text.starts_with(['}', ']', ')']),
     }
 }
 
@@ -555,7 +1437,7 @@ pub fn editor_overflow() {
         std::io::stdin().read_line(&mut input).unwrap();
         match input.trim().to_lowercase().as_str() {
             "a" => {
-                println!("let us never speak of this again");
+                say!("let us never speak of this again");
                 break;
             },
             "f" => {
@@ -568,12 +1450,39 @@ pub fn editor_overflow() {
 
 /// A nothing function that does nothing.
 /// Used to provide functionality for the `~nothing` command.
-pub fn nothing(file_buffer: &Vec<String>) {
+pub fn nothing(file_buffer: &[String]) {
     if file_buffer.is_empty() {
-        println!("no buffer contents");
+        say!("no buffer contents");
     }
     let buffer_contents: String = file_buffer.join("; ");
-    println!("doing nothing with {}", buffer_contents);
+    say!("doing nothing with {}", buffer_contents);
+}
+
+/// Wraps a typed `line` at word boundaries so that no line is longer than `text_width` characters,
+/// keeping the line's indentation on every wrapped line. A `text_width` of 0 means no wrapping.
+/// Words longer than `text_width` are left whole.
+pub fn wrap_line(line: &str, text_width: usize) -> Vec<String> {
+    if text_width == 0 || line.chars().count() <= text_width {
+        return vec![line.to_string()];
+    }
+
+    let indentation: String = line.chars().take_while(|c| c.is_whitespace()).collect();
+    let mut lines: Vec<String> = Vec::new();
+    let mut current = indentation.clone();
+
+    for word in line.split_whitespace() {
+        let current_width = current.chars().count();
+        if current_width > indentation.chars().count() && current_width + 1 + word.chars().count() > text_width {
+            lines.push(std::mem::replace(&mut current, indentation.clone()));
+        }
+        if current.chars().count() > indentation.chars().count() {
+            current.push(' ');
+        }
+        current.push_str(word);
+    }
+    lines.push(current);
+
+    lines
 }
 
 /// A helper function used for the ~substitute command.
@@ -602,34 +1511,353 @@ pub fn split_pattern_replacement(combined_args: &str) -> Vec<&str> {
     pattern_replacement
 }
 
+/// Splits a `command` into its arguments, the way every command in sued sees them.
+/// Arguments are separated by any amount of whitespace, and can be wrapped in
+/// single quotes (taken literally) or double quotes (where `\"` and `\\` are escapes).
+/// Outside of single quotes, a backslash only escapes whitespace, quotes, backslashes and `;`,
+/// so regex patterns like `\d+` and `a\/b` pass through untouched.
+/// Returns an error if a quote is left open.
+pub fn tokenize(command: &str) -> Result<Vec<String>, String> {
+    scan_tokens(command, true)
+}
+
+/// Takes the quotes and escapes out of `text` the same way `tokenize` does, but keeps whitespace
+/// outside of quotes as it is, instead of splitting there. Used for `~substitute`'s pattern and replacement.
+pub fn unquote(text: &str) -> Result<String, String> {
+    scan_tokens(text, false).map(|tokens| tokens.concat())
+}
+
+/// Does the work for `tokenize` and `unquote`. Without `split`, everything ends up in one token.
+fn scan_tokens(command: &str, split: bool) -> Result<Vec<String>, String> {
+    let mut tokens: Vec<String> = Vec::new();
+    let mut token = String::new();
+    let mut in_token = false;
+    let mut quote: Option<char> = None;
+    let mut chars = command.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some('\''), '\'') => quote = None,
+            (Some('\''), _) => token.push(c),
+            (_, '\\') => {
+                let escapable = match chars.peek() {
+                    Some(&next) if quote.is_some() => next == '"' || next == '\\',
+                    Some(&next) => next.is_whitespace() || matches!(next, '"' | '\'' | '\\' | ';'),
+                    None => false,
+                };
+                if escapable {
+                    token.extend(chars.next());
+                }
+                else {
+                    token.push(c);
+                }
+                in_token = true;
+            }
+            (Some('"'), '"') => quote = None,
+            (Some(_), _) => token.push(c),
+            (None, '"') | (None, '\'') => {
+                quote = Some(c);
+                in_token = true;
+            }
+            (None, _) if split && c.is_whitespace() => {
+                if in_token {
+                    tokens.push(std::mem::take(&mut token));
+                    in_token = false;
+                }
+            }
+            (None, _) => {
+                token.push(c);
+                in_token = true;
+            }
+        }
+    }
+
+    if let Some(open_quote) = quote {
+        return Err(format!("unclosed {} quote", if open_quote == '"' { "double" } else { "single" }));
+    }
+    if in_token {
+        tokens.push(token);
+    }
+
+    Ok(tokens)
+}
+
+/// Fills in an alias' `expansion` with the `arguments` it was run with.
+/// `$1` to `$9` are replaced with single arguments, and `$@` with all of them as they were typed.
+/// If the expansion has no placeholders, the arguments go on the end instead.
+pub fn expand_alias(expansion: &str, arguments: &str) -> Result<String, String> {
+    let placeholder = Regex::new(r"\$([1-9@])").unwrap();
+    if !placeholder.is_match(expansion) {
+        return Ok(format!("{} {}", expansion, arguments).trim_end().to_string());
+    }
+
+    let tokens = tokenize(arguments)?;
+    let expanded = placeholder.replace_all(expansion, |captures: &regex::Captures| {
+        match &captures[1] {
+            "@" => arguments.to_string(),
+            number => {
+                let token = tokens.get(number.parse::<usize>().unwrap() - 1).cloned().unwrap_or_default();
+                if token.contains(char::is_whitespace) {
+                    format!("\"{}\"", token.replace('\\', "\\\\").replace('"', "\\\""))
+                }
+                else {
+                    token
+                }
+            }
+        }
+    });
+    Ok(expanded.to_string())
+}
+
+/// Splits a chain of commands at the first `;` that isn't quoted or escaped.
+/// Returns the first command, and the rest of the chain if there is any.
+pub fn split_command_chain(chain: &str) -> (&str, Option<&str>) {
+    let mut quote: Option<char> = None;
+    let mut escaped = false;
+
+    for (i, c) in chain.char_indices() {
+        if escaped {
+            escaped = false;
+        }
+        else if quote == Some(c) {
+            quote = None;
+        }
+        else if quote == Some('\'') {
+            continue;
+        }
+        else if c == '\\' {
+            escaped = true;
+        }
+        else if quote.is_none() && (c == '"' || c == '\'') {
+            quote = Some(c);
+        }
+        else if quote.is_none() && c == ';' {
+            return (&chain[..i], Some(&chain[i + 1..]));
+        }
+    }
+
+    (chain, None)
+}
+
+/// A helper function used by all commands that take a line number.
+/// Returns the line number `specifier` refers to, where `.` is the `cursor`.
+pub fn parse_line_number(specifier: &str, cursor: usize) -> Option<usize> {
+    if specifier == "." {
+        return Some(cursor);
+    }
+    specifier.parse::<usize>().ok()
+}
+
 /// A helper function used by all commands with range specifiers.
-/// Returns the range of lines to operate on.
-pub fn parse_tilde_range(specifier: &str, buffer_len: usize) -> (usize, usize) {
+/// Returns the range of lines to operate on, where `.` is the `cursor`.
+pub fn parse_tilde_range(specifier: &str, buffer_len: usize, cursor: usize) -> (usize, usize) {
     let start_point = 1;
     let end_point = buffer_len;
 
-    if specifier.starts_with("~") {
-        if let Ok(end) = specifier.trim_start_matches("~").parse::<usize>() {
+    if specifier.starts_with('~') {
+        if let Some(end) = parse_line_number(specifier.trim_start_matches('~'), cursor) {
             return (start_point, end);
         }
     }
-    else if specifier.ends_with("~") {
-        if let Ok(start) = specifier.trim_end_matches("~").parse::<usize>() {
+    else if specifier.ends_with('~') {
+        if let Some(start) = parse_line_number(specifier.trim_end_matches('~'), cursor) {
             return (start, end_point);
         }
     }
-    else if specifier.contains("~") {
-        let range: Vec<&str> = specifier.split("~").collect();
-        if let Ok(start) = range[0].parse::<usize>() {
-            if let Ok(end) = range[1].parse::<usize>() {
+    else if specifier.contains('~') {
+        let range: Vec<&str> = specifier.split('~').collect();
+        if let Some(start) = parse_line_number(range[0], cursor) {
+            if let Some(end) = parse_line_number(range[1], cursor) {
                 return (start, end);
             }
         }
     }
-    else if let Ok(specified_line) = specifier.parse::<usize>() {
+    else if let Some(specified_line) = parse_line_number(specifier, cursor) {
         return (specified_line, specified_line);
     }
 
     (start_point, end_point)
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A root directory and a directory outside it, which are deleted again when the test is done.
+    struct Sandbox {
+        base: PathBuf,
+        root: PathBuf,
+        outside: PathBuf,
+    }
+
+    impl Sandbox {
+        fn new(name: &str) -> Sandbox {
+            let base = env::temp_dir().join(format!("sued-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&base);
+            fs::create_dir_all(base.join("root/sub")).unwrap();
+            fs::create_dir_all(base.join("outside")).unwrap();
+            fs::write(base.join("root/inside.txt"), "inside").unwrap();
+            let base = base.canonicalize().unwrap();
+            Sandbox { root: base.join("root"), outside: base.join("outside"), base }
+        }
+    }
+
+    impl Drop for Sandbox {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.base);
+        }
+    }
+
+    #[test]
+    fn confine_path_allows_files_inside_the_root() {
+        let sandbox = Sandbox::new("inside");
+        assert_eq!(confine_path("inside.txt", &sandbox.root), Ok(sandbox.root.join("inside.txt")));
+        assert_eq!(confine_path("sub/new.txt", &sandbox.root), Ok(sandbox.root.join("sub/new.txt")));
+    }
+
+    #[test]
+    fn confine_path_refuses_parent_directories() {
+        let sandbox = Sandbox::new("parent");
+        assert!(confine_path("../outside/new.txt", &sandbox.root).is_err());
+        assert!(confine_path("sub/../inside.txt", &sandbox.root).is_err());
+    }
+
+    #[test]
+    fn confine_path_checks_absolute_paths() {
+        let sandbox = Sandbox::new("absolute");
+        let inside = sandbox.root.join("inside.txt");
+        assert_eq!(confine_path(inside.to_str().unwrap(), &sandbox.root), Ok(inside.clone()));
+        assert!(confine_path(sandbox.outside.join("new.txt").to_str().unwrap(), &sandbox.root).is_err());
+        assert!(confine_path("/etc/passwd", &sandbox.root).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn confine_path_follows_directory_symlinks() {
+        let sandbox = Sandbox::new("directory-symlink");
+        std::os::unix::fs::symlink(&sandbox.outside, sandbox.root.join("escape")).unwrap();
+        std::os::unix::fs::symlink(sandbox.root.join("sub"), sandbox.root.join("shortcut")).unwrap();
+        assert!(confine_path("escape/new.txt", &sandbox.root).is_err());
+        assert_eq!(confine_path("shortcut/new.txt", &sandbox.root), Ok(sandbox.root.join("sub/new.txt")));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn confine_path_refuses_dangling_symlinks() {
+        let sandbox = Sandbox::new("dangling-symlink");
+        std::os::unix::fs::symlink(sandbox.outside.join("new.txt"), sandbox.root.join("link")).unwrap();
+        std::os::unix::fs::symlink(sandbox.root.join("missing.txt"), sandbox.root.join("inner")).unwrap();
+        std::os::unix::fs::symlink(sandbox.root.join("inside.txt"), sandbox.root.join("alias")).unwrap();
+        assert!(confine_path("link", &sandbox.root).is_err());
+        assert!(!sandbox.outside.join("new.txt").exists());
+        assert!(confine_path("inner", &sandbox.root).is_err());
+        assert_eq!(confine_path("alias", &sandbox.root), Ok(sandbox.root.join("inside.txt")));
+    }
+
+    fn tokens(command: &str) -> Vec<String> {
+        tokenize(command).unwrap()
+    }
+
+    #[test]
+    fn tokenize_splits_on_any_whitespace() {
+        assert_eq!(tokens("~show  1~3\tx"), ["~show", "1~3", "x"]);
+        assert!(tokens("   ").is_empty());
+    }
+
+    #[test]
+    fn tokenize_handles_quotes() {
+        assert_eq!(tokens("~open 'my file.txt'"), ["~open", "my file.txt"]);
+        assert_eq!(tokens("~open \"my file.txt\""), ["~open", "my file.txt"]);
+        assert_eq!(tokens("'a \\\" b'"), ["a \\\" b"]);
+        assert_eq!(tokens("\"a \\\" b\""), ["a \" b"]);
+        assert_eq!(tokens("a\"b c\"d"), ["ab cd"]);
+        assert_eq!(tokens("''"), [""]);
+    }
+
+    #[test]
+    fn tokenize_handles_escapes() {
+        assert_eq!(tokens("a\\ b"), ["a b"]);
+        assert_eq!(tokens("a\\;b"), ["a;b"]);
+        assert_eq!(tokens("\\\\d+ \\d+ a\\/b"), ["\\d+", "\\d+", "a\\/b"]);
+        assert_eq!(tokens("trailing\\"), ["trailing\\"]);
+    }
+
+    #[test]
+    fn tokenize_refuses_unclosed_quotes() {
+        assert!(tokenize("~open \"my file").is_err());
+        assert!(tokenize("~open 'my file").is_err());
+    }
+
+    #[test]
+    fn unquote_keeps_whitespace() {
+        assert_eq!(unquote("a  b/Z"), Ok(String::from("a  b/Z")));
+        assert_eq!(unquote("\"a;b\"/X"), Ok(String::from("a;b/X")));
+        assert_eq!(unquote("a\\;b/X"), Ok(String::from("a;b/X")));
+        assert!(unquote("'a/b").is_err());
+    }
+
+    #[test]
+    fn split_command_chain_splits_at_the_first_plain_semicolon() {
+        assert_eq!(split_command_chain("~save; ~show"), ("~save", Some(" ~show")));
+        assert_eq!(split_command_chain("~show"), ("~show", None));
+        assert_eq!(split_command_chain("a;"), ("a", Some("")));
+    }
+
+    #[test]
+    fn split_command_chain_skips_quoted_and_escaped_semicolons() {
+        assert_eq!(split_command_chain("~sub 1 a\\;b/c; ~show"), ("~sub 1 a\\;b/c", Some(" ~show")));
+        assert_eq!(split_command_chain("~sub 1 'a;b'/c;x"), ("~sub 1 'a;b'/c", Some("x")));
+        assert_eq!(split_command_chain("~sub 1 \"a;\\\"b\"/c;x"), ("~sub 1 \"a;\\\"b\"/c", Some("x")));
+        assert_eq!(split_command_chain("'a\\';b"), ("'a\\'", Some("b")));
+        assert_eq!(split_command_chain("\"unclosed;b"), ("\"unclosed;b", None));
+    }
+
+    #[test]
+    fn parse_search_context_reads_options() {
+        assert_eq!(parse_search_context(&["-C3", "x"]), Ok((Some((3, 3)), 1)));
+        assert_eq!(parse_search_context(&["-B", "2", "-A1", "x"]), Ok((Some((2, 1)), 3)));
+        assert_eq!(parse_search_context(&["--", "-C3"]), Ok((None, 1)));
+        assert!(parse_search_context(&["-B", "eta"]).is_err());
+    }
+
+    #[test]
+    fn parse_search_context_leaves_words_that_look_like_options() {
+        assert_eq!(parse_search_context(&["-Beta"]), Ok((None, 0)));
+        assert_eq!(parse_search_context(&["-C2", "-Alpha"]), Ok((Some((2, 2)), 1)));
+    }
+
+    /// Runs `edit` on a buffer holding just `line`, with a tab width of 4, and returns the line afterwards.
+    fn edited(line: &str, edit: impl FnOnce(&mut [String])) -> String {
+        let mut buffer = vec![line.to_string()];
+        edit(&mut buffer);
+        buffer.remove(0)
+    }
+
+    #[test]
+    fn indent_out_stops_at_the_text() {
+        assert_eq!(edited("  é", |b| indent(b, 1, -1, 4, false)), " é");
+        assert_eq!(edited("  é", |b| indent(b, 1, -4, 4, false)), "é");
+    }
+
+    #[test]
+    fn indent_handles_tabs_and_spaces_together() {
+        assert_eq!(edited(" \tx", |b| indent(b, 1, -1, 4, false)), "\tx");
+        assert_eq!(edited("\t  x", |b| indent(b, 1, -4, 4, false)), "  x");
+        assert_eq!(edited("\t  x", |b| indent(b, 1, 4, 4, false)), "\t\t  x");
+        assert_eq!(edited("\t  x", |b| indent(b, 1, 6, 4, true)), "      \t  x");
+    }
+
+    #[test]
+    fn remove_indentation_keeps_tabs_that_only_partly_fit() {
+        assert_eq!(remove_indentation("  \t", 3, 4), "\t");
+        assert_eq!(remove_indentation("\t", 2, 4), "\t");
+        assert_eq!(remove_indentation("  \t", 4, 4), "");
+    }
+
+    #[test]
+    fn retab_converts_both_ways() {
+        assert_eq!(edited("      x", |b| retab(b, 1, 4, false)), "\t  x");
+        assert_eq!(edited("\t  x", |b| retab(b, 1, 4, true)), "      x");
+        assert_eq!(edited(" \tx\ty", |b| retab(b, 1, 4, true)), "    x\ty");
+    }
+}
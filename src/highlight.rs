@@ -0,0 +1,576 @@
+//! Contains sued's syntax highlighting, which colours the code `~show` displays,
+//! and the `list` option, which makes invisible characters visible.
+//!
+//! This file is part of sued.
+//!
+//! Visit `main.rs` for context and usage.
+
+use std::ops::Range;
+use std::path::Path;
+use unicode_width::UnicodeWidthChar;
+
+/// The languages sued can highlight.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Language {
+    Rust,
+    Python,
+    Shell,
+    Markdown,
+    Json,
+    Toml,
+}
+
+impl Language {
+    /// Returns the language called `name`, which is how the `syntax` option names it.
+    pub fn from_name(name: &str) -> Option<Language> {
+        match name {
+            "rust" => Some(Language::Rust),
+            "python" => Some(Language::Python),
+            "shell" => Some(Language::Shell),
+            "markdown" => Some(Language::Markdown),
+            "json" => Some(Language::Json),
+            "toml" => Some(Language::Toml),
+            _ => None,
+        }
+    }
+}
+
+/// What a piece of highlighted text is, which decides its colour.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Style {
+    Keyword,
+    Type,
+    String,
+    Number,
+    Comment,
+    Literal,
+    Key,
+    Heading,
+    Code,
+    Invisible,
+}
+
+impl Style {
+    fn colour(self) -> &'static str {
+        match self {
+            Style::Keyword => "\x1b[35m",
+            Style::Type => "\x1b[36m",
+            Style::String => "\x1b[32m",
+            Style::Number => "\x1b[33m",
+            Style::Comment => "\x1b[90m",
+            Style::Literal => "\x1b[33m",
+            Style::Key => "\x1b[34m",
+            Style::Heading => "\x1b[1;34m",
+            Style::Code => "\x1b[32m",
+            Style::Invisible => "\x1b[2m",
+        }
+    }
+}
+
+/// A piece of a line to colour, from byte `start` up to byte `end`.
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub style: Style,
+}
+
+/// Works out the language of a file from its name, or from its shebang, which is on its `first_line`.
+pub fn detect_language(file_path: Option<&str>, first_line: Option<&str>) -> Option<Language> {
+    if let Some(path) = file_path.map(Path::new) {
+        let file_name = path.file_name().and_then(|file_name| file_name.to_str()).unwrap_or_default();
+        match file_name {
+            "Cargo.lock" => return Some(Language::Toml),
+            ".bashrc" | ".bash_profile" | ".profile" | ".zshrc" | "PKGBUILD" => return Some(Language::Shell),
+            _ => (),
+        }
+        let extension = path.extension().and_then(|extension| extension.to_str()).unwrap_or_default().to_lowercase();
+        match extension.as_str() {
+            "rs" => return Some(Language::Rust),
+            "py" | "pyi" | "pyw" => return Some(Language::Python),
+            "sh" | "bash" | "zsh" | "ksh" => return Some(Language::Shell),
+            "md" | "markdown" => return Some(Language::Markdown),
+            "json" => return Some(Language::Json),
+            "toml" => return Some(Language::Toml),
+            _ => (),
+        }
+    }
+
+    // `#!/usr/bin/env python3` names the interpreter last, `#!/bin/sh -e` names it first
+    let shebang = first_line?.strip_prefix("#!")?;
+    let mut words = shebang.split_whitespace().map(|word| word.rsplit('/').next().unwrap_or(word));
+    let interpreter = match words.next()? {
+        "env" => words.find(|word| !word.starts_with('-'))?,
+        interpreter => interpreter,
+    };
+    if interpreter.starts_with("python") {
+        Some(Language::Python)
+    }
+    else if ["sh", "bash", "zsh", "dash", "ksh"].contains(&interpreter) {
+        Some(Language::Shell)
+    }
+    else {
+        None
+    }
+}
+
+/// Colours the part of `line` in `range`, using the `spans` from `Highlighter::highlight`.
+pub fn paint(line: &str, range: Range<usize>, spans: &[Span]) -> String {
+    let mut painted = String::with_capacity(range.len());
+    let mut position = range.start;
+    for span in spans.iter().filter(|span| span.end > range.start && span.start < range.end) {
+        let start = span.start.max(range.start);
+        let end = span.end.min(range.end);
+        painted.push_str(&line[position..start]);
+        painted.push_str(span.style.colour());
+        painted.push_str(&line[start..end]);
+        painted.push_str("\x1b[0m");
+        position = end;
+    }
+    painted.push_str(&line[position..range.end]);
+    painted
+}
+
+/// A line with its invisible characters swapped for markers, for the `list` option.
+/// Tabs become `→`, trailing spaces `·`, no-break spaces `⍽`, control characters `^X`,
+/// and zero-width characters their code point, like `<U+200B>`.
+pub struct Visualised {
+    pub text: String,
+    /// Where each byte of the original line ended up in `text`, plus where the end did.
+    offsets: Vec<usize>,
+    markers: Vec<Span>,
+}
+
+/// Swaps the invisible characters in `line` for markers, with tabs as wide as `tab_width`.
+pub fn visualise(line: &str, tab_width: usize) -> Visualised {
+    let trailing = line.trim_end().len();
+    let mut text = String::with_capacity(line.len());
+    let mut offsets = Vec::with_capacity(line.len() + 1);
+    let mut markers = Vec::new();
+    let mut column = 0;
+
+    for (index, c) in line.char_indices() {
+        offsets.extend(std::iter::repeat_n(text.len(), c.len_utf8()));
+        let marker = match c {
+            '\t' => {
+                let width = tab_width.max(1) - column % tab_width.max(1);
+                Some(format!("→{}", " ".repeat(width - 1)))
+            }
+            ' ' if index >= trailing => Some(String::from("·")),
+            '\u{a0}' | '\u{202f}' => Some(String::from("⍽")),
+            '\u{200b}'..='\u{200f}' | '\u{2060}' | '\u{feff}' => Some(format!("<U+{:04X}>", c as u32)),
+            '\u{7f}' => Some(String::from("^?")),
+            c if (c as u32) < 0x20 => Some(format!("^{}", char::from(c as u8 + 0x40))),
+            c if c.is_control() => Some(format!("<U+{:04X}>", c as u32)),
+            _ => None,
+        };
+        match marker {
+            Some(marker) => {
+                column += marker.chars().count();
+                markers.push(Span { start: text.len(), end: text.len() + marker.len(), style: Style::Invisible });
+                text.push_str(&marker);
+            }
+            None => {
+                column += c.width().unwrap_or(0);
+                text.push(c);
+            }
+        }
+    }
+    offsets.push(text.len());
+
+    Visualised { text, offsets, markers }
+}
+
+impl Visualised {
+    /// Moves `spans` from the original line to where they are in `text`, and adds the markers,
+    /// which take priority over whatever they're in the middle of.
+    pub fn spans(&self, spans: Vec<Span>) -> Vec<Span> {
+        let mut moved: Vec<Span> = Vec::with_capacity(spans.len() + self.markers.len());
+        for span in spans {
+            let (span_start, end) = (self.offsets[span.start], self.offsets[span.end]);
+            let mut start = span_start;
+            for marker in self.markers.iter().filter(|marker| marker.start >= span_start && marker.end <= end) {
+                if marker.start > start {
+                    moved.push(Span { start, end: marker.start, style: span.style });
+                }
+                start = marker.end;
+            }
+            if end > start {
+                moved.push(Span { start, end, style: span.style });
+            }
+        }
+        moved.extend(self.markers.iter().map(|marker| Span { start: marker.start, end: marker.end, style: marker.style }));
+        moved.sort_by_key(|span| span.start);
+        moved
+    }
+}
+
+/// Returns a description of `c` for `~what`, if it's one of the characters that's hard to tell apart from others.
+pub fn describe_char(c: char) -> Option<&'static str> {
+    match c {
+        '\t' => Some("tab"),
+        ' ' => Some("space"),
+        '\u{a0}' => Some("no-break space"),
+        '\u{202f}' => Some("narrow no-break space"),
+        '\u{2000}'..='\u{200a}' | '\u{205f}' | '\u{3000}' => Some("wide or narrow space"),
+        '\u{200b}' => Some("zero-width space"),
+        '\u{200c}' => Some("zero-width non-joiner"),
+        '\u{200d}' => Some("zero-width joiner"),
+        '\u{200e}' | '\u{200f}' => Some("direction mark"),
+        '\u{2060}' => Some("word joiner"),
+        '\u{feff}' => Some("byte order mark"),
+        c if c.is_control() => Some("control character"),
+        _ => None,
+    }
+}
+
+/// What a language's code looks like, as far as the highlighter cares.
+struct Syntax {
+    line_comment: Option<&'static str>,
+    block_comment: bool,
+    quotes: &'static [char],
+    triple_quotes: bool,
+    keywords: &'static [&'static str],
+    literals: &'static [&'static str],
+}
+
+fn syntax(language: Language) -> Syntax {
+    match language {
+        Language::Rust => Syntax {
+            line_comment: Some("//"),
+            block_comment: true,
+            quotes: &['"'],
+            triple_quotes: false,
+            keywords: &[
+                "as", "async", "await", "break", "const", "continue", "crate", "dyn", "else", "enum", "extern", "fn", "for",
+                "if", "impl", "in", "let", "loop", "match", "mod", "move", "mut", "pub", "ref", "return", "self", "Self",
+                "static", "struct", "super", "trait", "type", "unsafe", "use", "where", "while",
+            ],
+            literals: &["true", "false"],
+        },
+        Language::Python => Syntax {
+            line_comment: Some("#"),
+            block_comment: false,
+            quotes: &['"', '\''],
+            triple_quotes: true,
+            keywords: &[
+                "and", "as", "assert", "async", "await", "break", "class", "continue", "def", "del", "elif", "else", "except",
+                "finally", "for", "from", "global", "if", "import", "in", "is", "lambda", "nonlocal", "not", "or", "pass",
+                "raise", "return", "try", "while", "with", "yield",
+            ],
+            literals: &["True", "False", "None"],
+        },
+        Language::Shell => Syntax {
+            line_comment: Some("#"),
+            block_comment: false,
+            quotes: &['"', '\''],
+            triple_quotes: false,
+            keywords: &[
+                "case", "do", "done", "elif", "else", "esac", "export", "fi", "for", "function", "if", "in", "local",
+                "return", "then", "until", "while",
+            ],
+            literals: &[],
+        },
+        Language::Json => Syntax {
+            line_comment: None,
+            block_comment: false,
+            quotes: &['"'],
+            triple_quotes: false,
+            keywords: &[],
+            literals: &["true", "false", "null"],
+        },
+        Language::Toml | Language::Markdown => Syntax {
+            line_comment: Some("#"),
+            block_comment: false,
+            quotes: &['"', '\''],
+            triple_quotes: true,
+            keywords: &[],
+            literals: &["true", "false", "inf", "nan"],
+        },
+    }
+}
+
+/// What carries on from one line to the next.
+#[derive(Clone, Copy)]
+enum Continued {
+    Nothing,
+    /// A Rust block comment, nested this deep.
+    BlockComment(usize),
+    /// A string that ends with this delimiter.
+    String(&'static str),
+    /// A Markdown fenced code block.
+    CodeBlock,
+}
+
+/// Highlights a file a line at a time.
+/// Strings and comments can go on for more than one line, so lines have to be given in order, from the start of the file.
+pub struct Highlighter {
+    language: Language,
+    continued: Continued,
+}
+
+impl Highlighter {
+    pub fn new(language: Language) -> Highlighter {
+        Highlighter {
+            language,
+            continued: Continued::Nothing,
+        }
+    }
+
+    /// Returns the spans to colour in `line`, in order.
+    pub fn highlight(&mut self, line: &str) -> Vec<Span> {
+        match self.language {
+            Language::Markdown => self.highlight_markdown(line),
+            language => self.highlight_code(line, &syntax(language)),
+        }
+    }
+
+    fn highlight_code(&mut self, line: &str, syntax: &Syntax) -> Vec<Span> {
+        let mut spans = Vec::new();
+        let mut position = 0;
+
+        match self.continued {
+            Continued::Nothing | Continued::CodeBlock => (),
+            Continued::BlockComment(depth) => {
+                position = self.scan_block_comment(line, 0, depth);
+                spans.push(Span { start: 0, end: position, style: Style::Comment });
+            }
+            Continued::String(delimiter) => {
+                position = self.scan_string(line, 0, delimiter);
+                spans.push(Span { start: 0, end: position, style: Style::String });
+            }
+        }
+
+        let at_line_start = |position: usize| line[..position].trim().is_empty();
+        while position < line.len() {
+            if !matches!(self.continued, Continued::Nothing) {
+                break;
+            }
+            let rest = &line[position..];
+            let c = rest.chars().next().unwrap_or_default();
+            let start = position;
+
+            // Shell only treats # as a comment at the start of a word, so $# and foo#bar aren't comments
+            let comment_allowed = self.language != Language::Shell || position == 0 || line[..position].ends_with(char::is_whitespace);
+            if syntax.line_comment.is_some_and(|comment| rest.starts_with(comment)) && comment_allowed {
+                spans.push(Span { start, end: line.len(), style: Style::Comment });
+                break;
+            }
+            if syntax.block_comment && rest.starts_with("/*") {
+                position = self.scan_block_comment(line, position + 2, 1);
+                spans.push(Span { start, end: position, style: Style::Comment });
+                continue;
+            }
+
+            if syntax.quotes.contains(&c) {
+                let delimiter = match (c, syntax.triple_quotes) {
+                    ('"', true) if rest.starts_with("\"\"\"") => "\"\"\"",
+                    ('\'', true) if rest.starts_with("'''") => "'''",
+                    ('"', _) => "\"",
+                    _ => "'",
+                };
+                position = self.scan_string(line, position + delimiter.len(), delimiter);
+                // Strings can only go on to the next line in Rust, or with triple quotes
+                if delimiter.len() == 1 && self.language != Language::Rust {
+                    self.continued = Continued::Nothing;
+                }
+                let is_key = self.language == Language::Json && line[position..].trim_start().starts_with(':');
+                spans.push(Span { start, end: position, style: if is_key { Style::Key } else { Style::String } });
+                continue;
+            }
+
+            if self.language == Language::Rust && c == '\'' {
+                // 'a' is a character, but 'a on its own is a lifetime
+                if let Some(length) = char_literal_length(rest) {
+                    position += length;
+                    spans.push(Span { start, end: position, style: Style::String });
+                    continue;
+                }
+            }
+            if self.language == Language::Rust && (rest.starts_with("#[") || rest.starts_with("#![")) {
+                position = rest.find(']').map_or(line.len(), |end| position + end + 1);
+                spans.push(Span { start, end: position, style: Style::Type });
+                continue;
+            }
+            if self.language == Language::Python && c == '@' && at_line_start(position) {
+                position += word_length(&rest[1..]) + 1;
+                spans.push(Span { start, end: position, style: Style::Type });
+                continue;
+            }
+            if self.language == Language::Shell && c == '$' {
+                position += variable_length(rest);
+                spans.push(Span { start, end: position, style: Style::Literal });
+                continue;
+            }
+            if self.language == Language::Toml && c == '[' && at_line_start(position) {
+                position = rest.rfind(']').map_or(line.len(), |end| position + end + 1);
+                spans.push(Span { start, end: position, style: Style::Heading });
+                continue;
+            }
+
+            let follows_word = line[..position].chars().next_back().is_some_and(is_word_char);
+            if c.is_ascii_digit() && !follows_word {
+                position += rest.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '.')).unwrap_or(rest.len());
+                spans.push(Span { start, end: position, style: Style::Number });
+                continue;
+            }
+            if is_word_char(c) && !follows_word {
+                let word = &rest[..word_length(rest)];
+                position += word.len();
+                let after = line[position..].trim_start();
+                let style = if self.language == Language::Toml && at_line_start(start) && (after.starts_with('=') || after.starts_with('.')) {
+                    Some(Style::Key)
+                }
+                else if syntax.keywords.contains(&word) {
+                    Some(Style::Keyword)
+                }
+                else if syntax.literals.contains(&word) {
+                    Some(Style::Literal)
+                }
+                else if self.language == Language::Rust && line[position..].starts_with('!') {
+                    // Macros
+                    position += 1;
+                    Some(Style::Keyword)
+                }
+                else if matches!(self.language, Language::Rust | Language::Python) && word.starts_with(|c: char| c.is_uppercase()) {
+                    Some(Style::Type)
+                }
+                else {
+                    None
+                };
+                if let Some(style) = style {
+                    spans.push(Span { start, end: position, style });
+                }
+                continue;
+            }
+
+            position += c.len_utf8();
+        }
+        spans
+    }
+
+    fn highlight_markdown(&mut self, line: &str) -> Vec<Span> {
+        let trimmed = line.trim_start();
+        let whole_line = |style| vec![Span { start: 0, end: line.len(), style }];
+
+        if matches!(self.continued, Continued::CodeBlock) {
+            if trimmed.starts_with("```") || trimmed.starts_with("~~~") {
+                self.continued = Continued::Nothing;
+            }
+            return whole_line(Style::Code);
+        }
+        if trimmed.starts_with("```") || trimmed.starts_with("~~~") {
+            self.continued = Continued::CodeBlock;
+            return whole_line(Style::Code);
+        }
+        let hashes = trimmed.len() - trimmed.trim_start_matches('#').len();
+        if (1..=6).contains(&hashes) && trimmed[hashes..].chars().next().is_none_or(char::is_whitespace) {
+            return whole_line(Style::Heading);
+        }
+        if trimmed.starts_with('>') {
+            return whole_line(Style::Comment);
+        }
+
+        let mut spans = Vec::new();
+        let indent = line.len() - trimmed.len();
+        let numbered = trimmed.find(|c: char| !c.is_ascii_digit()).filter(|&digits| digits > 0 && trimmed[digits..].starts_with(". "));
+        if let Some(digits) = numbered {
+            spans.push(Span { start: indent, end: indent + digits + 1, style: Style::Keyword });
+        }
+        else if ["- ", "* ", "+ "].iter().any(|marker| trimmed.starts_with(marker)) {
+            spans.push(Span { start: indent, end: indent + 1, style: Style::Keyword });
+        }
+
+        let mut position = 0;
+        while let Some(start) = line[position..].find('`').map(|start| position + start) {
+            let ticks = line[start..].len() - line[start..].trim_start_matches('`').len();
+            let delimiter = &line[start..start + ticks];
+            match line[start + ticks..].find(delimiter) {
+                Some(end) => {
+                    position = start + ticks + end + ticks;
+                    spans.push(Span { start, end: position, style: Style::Code });
+                }
+                None => break,
+            }
+        }
+        spans
+    }
+
+    /// Finds the end of a block comment that starts before `position` in `line`, `depth` comments deep,
+    /// since Rust block comments can be nested. If it doesn't end on this line, it carries on to the next.
+    fn scan_block_comment(&mut self, line: &str, mut position: usize, mut depth: usize) -> usize {
+        while position < line.len() {
+            if line[position..].starts_with("/*") {
+                depth += 1;
+                position += 2;
+            }
+            else if line[position..].starts_with("*/") {
+                depth -= 1;
+                position += 2;
+                if depth == 0 {
+                    self.continued = Continued::Nothing;
+                    return position;
+                }
+            }
+            else {
+                position += line[position..].chars().next().map_or(1, char::len_utf8);
+            }
+        }
+        self.continued = Continued::BlockComment(depth);
+        line.len()
+    }
+
+    /// Finds the end of a string that ends with `delimiter`, starting at `position` in `line`.
+    /// If it doesn't end on this line, it carries on to the next.
+    fn scan_string(&mut self, line: &str, mut position: usize, delimiter: &'static str) -> usize {
+        // Single quoted strings in shell and TOML are literal, so backslashes don't escape anything
+        let escapes = delimiter != "'" || self.language == Language::Python;
+        while position < line.len() {
+            let rest = &line[position..];
+            if rest.starts_with(delimiter) {
+                self.continued = Continued::Nothing;
+                return position + delimiter.len();
+            }
+            let c = rest.chars().next().unwrap_or_default();
+            position += c.len_utf8();
+            if c == '\\' && escapes {
+                position += line[position..].chars().next().map_or(0, char::len_utf8);
+            }
+        }
+        self.continued = Continued::String(delimiter);
+        line.len()
+    }
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+fn word_length(text: &str) -> usize {
+    text.find(|c: char| !is_word_char(c)).unwrap_or(text.len())
+}
+
+/// Returns how long a Rust character literal at the start of `text` is, or `None` if it's a lifetime instead.
+fn char_literal_length(text: &str) -> Option<usize> {
+    let mut chars = text.char_indices().skip(1);
+    let (_, c) = chars.next()?;
+    if c == '\\' {
+        return text[2..].find('\'').map(|end| end + 3);
+    }
+    match chars.next() {
+        Some((end, '\'')) => Some(end + 1),
+        _ => None,
+    }
+}
+
+/// Returns how long the shell variable at the start of `text` is, like `$HOME`, `${HOME}`, `$1` or `$@`.
+fn variable_length(text: &str) -> usize {
+    let rest = &text[1..];
+    if rest.starts_with('{') {
+        return rest.find('}').map_or(text.len(), |end| end + 2);
+    }
+    match rest.chars().next() {
+        Some(c) if c.is_ascii_digit() || "@*#?$!-".contains(c) => 2,
+        Some(_) => word_length(rest) + 1,
+        None => 1,
+    }
+}
@@ -4,20 +4,71 @@
 //! 
 //! sued is free software licensed under the WTFPL.
 
+use std::collections::BTreeMap;
 use std::env;
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use shellexpand::tilde;
 use linefeed::{Interface, ReadResult};
 
+// `say!` is used everywhere instead of `println!`, so it has to come first.
+#[macro_use]
+mod output;
 // Please see the corresponding `functions.rs` file for those definitions.
 mod functions;
 use functions as suedfn;
+mod completion;
+mod options;
+mod scripting;
+mod server;
+mod cli;
+mod pager;
+mod highlight;
+use options::{OptionValue, Options};
+use cli::StartupInput;
 
 /// This struct is used to represent the file buffer.
 /// `contents` will contain the text contents of the file as a Vec,
 /// and `file_path` will, obviously, contain the file path.
+/// `cursor` is the current line, the one `.` refers to in line numbers and ranges.
+/// It's the line most recently typed or operated on, and is 0 if the buffer is empty.
+/// `options` holds the options set with `~setlocal`, which override the editor's options for this buffer.
+/// `readonly` buffers can be looked at, searched and copied from, but not changed or saved, see `~readonly`.
 struct FileBuffer {
     contents: Vec<String>,
     file_path: Option<String>,
+    cursor: usize,
+    options: Options,
+    readonly: bool,
+}
+
+impl FileBuffer {
+    fn new() -> FileBuffer {
+        FileBuffer {
+            contents: Vec::new(),
+            file_path: None,
+            cursor: 0,
+            options: Options::empty(),
+            readonly: false,
+        }
+    }
+
+    /// Returns the value of the option `name` for this buffer,
+    /// which is its local value if it has one and the editor's value otherwise.
+    fn option<'a>(&'a self, editor_options: &'a Options, name: &str) -> &'a OptionValue {
+        self.options.get(name)
+            .or_else(|| editor_options.get(name))
+            .unwrap_or_else(|| panic!("{} should be a registered option", name))
+    }
+
+
+    /// Moves the cursor to `line_number`, if that line is in the buffer.
+    fn move_cursor(&mut self, line_number: usize) {
+        if (1..=self.contents.len()).contains(&line_number) {
+            self.cursor = line_number;
+        }
+    }
 }
 
 impl std::fmt::Display for FileBuffer {
@@ -26,6 +77,54 @@ impl std::fmt::Display for FileBuffer {
     }
 }
 
+/// This struct holds everything about the editing session that isn't part of the buffer.
+/// `last_command` is what `~repeat` re-runs.
+/// `history` is kept between sessions.
+/// `options` holds every option, as set with `~set` or the config file.
+/// `aliases` and `macros` are defined with `~alias` and `~macro`, or in the config file.
+/// `recording` is the name and lines of the macro being recorded, if there is one.
+/// `depth` counts how many aliases and macros deep the current command is, so they can't run themselves forever.
+/// `plugins` and `scripts` are the names of the plugins and script commands found at startup, for `~` and tab completion.
+/// `interactive` is false in server and filter mode, where there's nobody at the keyboard to answer questions.
+/// `buffers` holds every open buffer, and `current_buffer` is the index of the one being edited.
+/// The buffer being edited is taken out of `buffers` and passed around on its own, leaving an empty buffer in its place,
+/// so use `switch_buffer` to change buffers rather than indexing `buffers` directly.
+/// `restricted` is the directory files have to be in, in restricted mode, where shell commands and plugins aren't allowed either.
+struct EditorState {
+    prompt: String,
+    prefix: String,
+    clipboard: suedfn::Clipboard,
+    last_command: Option<String>,
+    history: Vec<String>,
+    options: Options,
+    aliases: BTreeMap<String, String>,
+    macros: BTreeMap<String, Vec<String>>,
+    recording: Option<(String, Vec<String>)>,
+    depth: usize,
+    plugins: Vec<String>,
+    scripts: Vec<String>,
+    interactive: bool,
+    buffers: Vec<FileBuffer>,
+    current_buffer: usize,
+    restricted: Option<PathBuf>,
+}
+
+/// Commands that ask for more input, which only works when someone's at the keyboard.
+const INTERACTIVE_COMMANDS: [&str; 4] = ["bsod", "correct", "insert", "replace"];
+
+/// Commands that change the buffer or its file, which read-only buffers refuse.
+/// Commands that only sometimes change the buffer, like plugins and scripts, check for themselves.
+const MUTATING_COMMANDS: [&str; 15] = [
+    "clear", "correct", "del", "delete", "indent", "insert", "paste",
+    "replace", "retab", "runhere", "save", "sub", "substitute", "swap", "write",
+];
+
+/// Commands that could get around restricted mode, by running other programs.
+const RESTRICTED_COMMANDS: [&str; 2] = ["run", "runhere"];
+
+/// How many aliases, macros and history entries deep a command can be before sued gives up on it.
+const MAX_DEPTH: usize = 32;
+
 enum ExitStatus {
     Success,
     Failure,
@@ -34,143 +133,607 @@ enum ExitStatus {
 /// It's the main function.
 /// I don't know what you expected.
 fn main() {
-    suedfn::startup_message();
+    let mut args = env::args();
+    // Like red is to ed, running sued as rsued means restricted mode
+    let invoked_as_rsued = args.next().is_some_and(|program| Path::new(&program).file_stem().is_some_and(|stem| stem == "rsued"));
+    let mut cli_options = match cli::parse_args(args) {
+        Ok(cli_options) => cli_options,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+    if cli_options.help {
+        println!("{}", cli::usage());
+        return;
+    }
+    if cli_options.version {
+        println!("sued v{}", env!("CARGO_PKG_VERSION"));
+        return;
+    }
+    if invoked_as_rsued && cli_options.restricted.is_none() {
+        cli_options.restricted = Some(None);
+    }
+    let restricted = cli_options.restricted.as_ref().map(|root| match restricted_root(root.as_deref()) {
+        Ok(root) => root,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    });
 
-    let interface = Interface::new("sued").unwrap();
-    let mut buffer = FileBuffer {
-        contents: Vec::new(),
-        file_path: None,
+    let mut state = EditorState {
+        prompt: String::new(),
+        prefix: String::from("~"),
+        clipboard: suedfn::Clipboard::new(),
+        last_command: None,
+        history: Vec::new(),
+        options: Options::defaults(),
+        aliases: BTreeMap::new(),
+        macros: BTreeMap::new(),
+        recording: None,
+        depth: 0,
+        plugins: if restricted.is_some() { Vec::new() } else { suedfn::plugin_names() },
+        scripts: scripting::script_names(),
+        interactive: true,
+        buffers: Vec::new(),
+        current_buffer: 0,
+        restricted,
     };
 
-    let mut prompt = String::new();
-    let mut prefix = String::from("~");
+    // Piping into sued with -e or -f means it's being used as a filter, even without - or --filter
+    let filter = cli_options.filter || (!cli_options.startup_input.is_empty() && !std::io::stdin().is_terminal());
+    if cli_options.server.is_some() || filter {
+        // Nothing can be printed to stdout before the server starts, in case that's where the client is listening,
+        // and in filter mode, stdout is for the buffer
+        output::start_capture();
+        state.interactive = false;
+    }
+
+    if !cli_options.no_config {
+        if let Some(config) = suedfn::config_file_path().and_then(|config_path| suedfn::load_config(&config_path)) {
+            apply_config(config, &mut state);
+        }
+    }
+
+    if !cli_options.quiet && state.interactive && state.options.get("startup_message").is_some_and(OptionValue::as_bool) {
+        suedfn::startup_message(&state.prefix);
+    }
+
+    for file in &cli_options.files {
+        if let Err(e) = resolve_path(&file.path, &state) {
+            say!("{}", e);
+            continue;
+        }
+        let mut buffer = FileBuffer::new();
+        buffer.contents = suedfn::open(&file.path, &mut buffer.file_path);
+        buffer.file_path = Some(file.path.clone());
+        buffer.cursor = buffer.contents.len();
+        if let Some(line) = file.line {
+            buffer.move_cursor(line);
+        }
+        buffer.readonly = cli_options.readonly;
+        detect_line_ending(&mut buffer, &file.path);
+        state.buffers.push(buffer);
+    }
+    if state.buffers.is_empty() {
+        state.buffers.push(FileBuffer::new());
+    }
+    // The current buffer is taken out of the list while it's being edited, see `EditorState`
+    let mut buffer = std::mem::replace(&mut state.buffers[0], FileBuffer::new());
+
+    if let Some(socket_path) = cli_options.server {
+        server::serve(socket_path.as_deref(), &mut buffer, &mut state);
+        return;
+    }
+
+    if filter {
+        run_filter(&cli_options.startup_input, cli_options.files.is_empty(), &mut buffer, &mut state);
+        return;
+    }
 
-    let args: Vec<String> = env::args().collect();
-    if args.len() >= 2 {
-        buffer.contents = suedfn::open(&args[1], &mut buffer.file_path);
-        buffer.file_path = Some(args[1].clone());
+    let interface = Interface::new("sued").unwrap();
+
+    let history_path = suedfn::history_file_path();
+    if let Some(history_path) = &history_path {
+        state.history = suedfn::load_history(history_path);
+        for entry in &state.history {
+            interface.add_history(entry.clone());
+        }
     }
 
     ctrlc::set_handler(|| {
-        println!("use ~exit to exit sued");
+        say!("use ~exit to exit sued");
     })
     .expect("Error setting Ctrl-C handler");
 
-    while let ReadResult::Input(line) = interface.read_line().unwrap() {
-        let command = line.trim_end().to_string();
-        interface.add_history_unique(command.clone());
-        let command_args = command.split(' ').collect::<Vec<&str>>();
-        if command_args[0] == prefix {
-            suedfn::command_list();
-        }
-        else if command.starts_with(&prefix) {
-            if let ExitStatus::Failure = process_command(command_args, &mut buffer, &mut prompt, &mut prefix){
+    if let ExitStatus::Success = run_startup_input(&cli_options.startup_input, &mut buffer, &mut state) {
+        loop {
+            interface.set_prompt(&state.prompt).unwrap_or_default();
+            interface.set_history_size(history_size(&state));
+            let extra_commands: Vec<String> = state.aliases.keys().chain(state.scripts.iter()).chain(state.plugins.iter()).cloned().collect();
+            interface.set_completer(Arc::new(completion::SuedCompleter::new(&state.prefix, &buffer.contents, extra_commands, state.restricted.is_none())));
+            let indentation = auto_indentation(&buffer, &state);
+            if !indentation.is_empty() {
+                interface.set_buffer(&indentation).unwrap_or_default();
+            }
+            let ReadResult::Input(line) = interface.read_line().unwrap() else {
                 break;
+            };
+            let command = settle_typed_line(line.trim_end().to_string(), &indentation, &buffer, &state);
+            if buffer.option(&state.options, "history_text").as_bool() || command.starts_with(&state.prefix) {
+                interface.add_history_unique(command.clone());
+                let history_size = history_size(&state);
+                suedfn::record_history(&mut state.history, &command, history_size);
+            }
+            let was_recording = state.recording.is_some();
+            if let ExitStatus::Failure = process_line(&command, &mut buffer, &mut state) {
+                break;
+            }
+            // The line that started recording isn't part of the macro, and neither is the one that stopped it
+            if let (true, Some((_, macro_lines))) = (was_recording, &mut state.recording) {
+                macro_lines.push(command);
+            }
+        }
+    }
+
+    if let Some(history_path) = &history_path {
+        suedfn::save_history(&state.history, history_path, history_size(&state));
+    }
+}
+
+/// Returns how many history entries to keep, according to the `history_size` option.
+fn history_size(state: &EditorState) -> usize {
+    state.options.get("history_size").map_or(0, OptionValue::as_number)
+}
+
+/// Sets the `line_ending` option for `buffer` to match the file at `file_path`,
+/// so files with Windows line endings are saved with them too.
+fn detect_line_ending(buffer: &mut FileBuffer, file_path: &str) {
+    buffer.options.unset("line_ending");
+    if suedfn::has_crlf_line_endings(file_path) {
+        buffer.options.set("line_ending", "crlf", true).unwrap_or_default();
+    }
+}
+
+/// Runs sued as a filter, like sed.
+/// The buffer comes from stdin if `from_stdin` is set, otherwise it's the file that was opened.
+/// Each line of `startup_input` is run in order, with messages going to stderr, then the buffer is written to stdout.
+fn run_filter(startup_input: &[StartupInput], from_stdin: bool, buffer: &mut FileBuffer, state: &mut EditorState) {
+    use std::io::{Read, Write};
+
+    if from_stdin {
+        let mut input = String::new();
+        if let Err(e) = std::io::stdin().read_to_string(&mut input) {
+            eprintln!("couldn't read stdin, because {}", e);
+            std::process::exit(1);
+        }
+        if input.contains("\r\n") {
+            buffer.options.set("line_ending", "crlf", true).unwrap_or_default();
+        }
+        buffer.contents = input.lines().map(String::from).collect();
+        buffer.cursor = buffer.contents.len();
+    }
+
+    output::flush_to_stderr();
+    run_startup_input(startup_input, buffer, state);
+
+    let line_ending = if buffer.option(&state.options, "line_ending").as_text() == "crlf" { "\r\n" } else { "\n" };
+    let mut stdout = std::io::stdout().lock();
+    for line in &buffer.contents {
+        if write!(stdout, "{}{}", line, line_ending).is_err() {
+            break;
+        }
+    }
+}
+
+/// Runs each line of `startup_input` from `-e` and `-f` in order, stopping if one of them exits sued.
+/// In filter mode, messages go to stderr as each line finishes.
+fn run_startup_input(startup_input: &[StartupInput], buffer: &mut FileBuffer, state: &mut EditorState) -> ExitStatus {
+    for input in startup_input {
+        let line = match input {
+            StartupInput::Command(command) if !command.starts_with(&state.prefix) => format!("{}{}", state.prefix, command),
+            StartupInput::Command(line) | StartupInput::Line(line) => line.clone(),
+        };
+        let status = process_line(&line, buffer, state);
+        output::flush_to_stderr();
+        if let ExitStatus::Failure = status {
+            return ExitStatus::Failure;
+        }
+    }
+    ExitStatus::Success
+}
+
+/// Applies the settings from sued's config file to the `state`.
+/// `prefix` and `prompt` are set directly, the `aliases` and `macros` tables define aliases and macros,
+/// and everything else is an option, as if set with `~set`.
+/// Settings that sued doesn't know, or that have the wrong type, are reported and skipped.
+fn apply_config(config: toml::Table, state: &mut EditorState) {
+    use toml::Value;
+
+    for (key, value) in config {
+        let result = match (key.as_str(), &value) {
+            ("prefix", Value::String(prefix)) if !prefix.is_empty() => {
+                state.prefix = prefix.clone();
+                Ok(())
+            }
+            ("prompt", Value::String(prompt)) => {
+                state.prompt = prompt.clone();
+                Ok(())
+            }
+            ("aliases", Value::Table(aliases)) => {
+                for (name, expansion) in aliases {
+                    match (check_alias_name(name), expansion) {
+                        (Ok(()), Value::String(expansion)) => {
+                            state.aliases.insert(name.clone(), expansion.clone());
+                        }
+                        (Err(e), _) => say!("config alias {} was skipped, because {}", name, e),
+                        (_, expansion) => say!("config alias {} was skipped, because {} isn't a string", name, expansion),
+                    }
+                }
+                Ok(())
+            }
+            ("macros", Value::Table(macros)) => {
+                for (name, lines) in macros {
+                    let lines: Option<Vec<String>> = lines.as_array()
+                        .and_then(|lines| lines.iter().map(|line| line.as_str().map(String::from)).collect());
+                    match lines {
+                        Some(lines) => {
+                            state.macros.insert(name.clone(), lines);
+                        }
+                        None => say!("config macro {} was skipped, because it isn't a list of lines", name),
+                    }
+                }
+                Ok(())
             }
+            (_, Value::String(text)) => state.options.set(&key, text, false).map(|_| ()),
+            (_, Value::Boolean(_) | Value::Integer(_)) => state.options.set(&key, &value.to_string(), false).map(|_| ()),
+            _ => Err(format!("{} can't be set to {}", key, value)),
+        };
+        if let Err(e) = result {
+            say!("config setting {} was skipped, because {}", key, e);
+        }
+    }
+}
+
+/// Process a `line` of input, which is either text to add to the buffer or a chain of commands.
+fn process_line(line: &str, buffer: &mut FileBuffer, state: &mut EditorState) -> ExitStatus {
+    if line.starts_with(&state.prefix) {
+        return run_chain(line, buffer, state, true);
+    }
+    else if buffer.readonly {
+        say!("this buffer is read-only, so text can't be added to it, try ~readonly off");
+    }
+    else {
+        // A backslash at the start of the text escapes the prefix, so the rest of the line is typed as-is
+        let text = line.trim_start_matches([' ', '\t']);
+        let line = match text.strip_prefix('\\') {
+            Some(escaped) if escaped.trim_start_matches('\\').starts_with(&state.prefix) => {
+                format!("{}{}", &line[..line.len() - text.len()], escaped)
+            }
+            _ => line.to_string(),
+        };
+        let text_width = buffer.option(&state.options, "text_width").as_number();
+        buffer.contents.extend(suedfn::wrap_line(&line, text_width));
+        buffer.cursor = buffer.contents.len();
+    }
+    ExitStatus::Success
+}
+
+/// Runs a `chain` of commands separated with `;`, where the prefix is optional on every command after the first.
+/// With `raw_tail`, everything after `~run`, `~runhere`, `~alias` or `~eval` belongs to that command, so the chain stops there.
+/// That's how typed lines work, but alias expansions always split at `;`, so they can carry on after a shell command.
+fn run_chain(chain: &str, buffer: &mut FileBuffer, state: &mut EditorState, raw_tail: bool) -> ExitStatus {
+    let mut remaining = Some(chain);
+    while let Some(chain) = remaining {
+        let chain = chain.trim();
+        let name = chain.strip_prefix(&state.prefix).unwrap_or(chain).split_whitespace().next().unwrap_or_default();
+        let (command, rest) = match suedfn::resolve_command(name) {
+            suedfn::CommandName::Known("run" | "runhere" | "alias" | "eval") if raw_tail => (chain, None),
+            _ => suedfn::split_command_chain(chain),
+        };
+        remaining = rest;
+
+        let command = command.trim();
+        if command.is_empty() {
+            continue;
+        }
+        let command = if command.starts_with(&state.prefix) {
+            command.to_string()
         }
         else {
-            let to_write = command_args.clone().join(" ");
-            buffer.contents.push(to_write);
+            format!("{}{}", state.prefix, command)
+        };
+        if let ExitStatus::Failure = run_command(&command, buffer, state) {
+            return ExitStatus::Failure;
         }
-        interface.set_prompt(&prompt).unwrap_or_default();
     }
+    ExitStatus::Success
 }
 
-/// Process an editing command passed from `command_args`.
-/// Requires mutable access to `buffer`, `prompt` and `prefix`, since this function will need to modify these.
+/// Runs a single `command`, handling the count prefix (`~3 indent . 4`), aliases and `~repeat`/`~.`
+/// before passing it on to `process_command`.
+fn run_command(command: &str, buffer: &mut FileBuffer, state: &mut EditorState) -> ExitStatus {
+    if command.split_whitespace().next() == Some(state.prefix.as_str()) {
+        suedfn::command_list();
+        if !state.aliases.is_empty() {
+            say!("aliases: {}", state.aliases.keys().cloned().collect::<Vec<String>>().join(", "));
+        }
+        if !state.scripts.is_empty() {
+            say!("scripts: {}", state.scripts.join(", "));
+        }
+        if !state.plugins.is_empty() {
+            say!("plugins: {}", state.plugins.join(", "));
+        }
+        return ExitStatus::Success;
+    }
+
+    let unprefixed = command.strip_prefix(&state.prefix).unwrap_or(command);
+    let (name, arguments) = unprefixed.split_once(char::is_whitespace).unwrap_or((unprefixed, ""));
+
+    if let Ok(count) = name.parse::<usize>() {
+        if arguments.trim().is_empty() {
+            say!("do what {} times?", count);
+            return ExitStatus::Success;
+        }
+        let repeated_name = arguments.split_whitespace().next().unwrap_or_default();
+        if let (suedfn::CommandName::Known("." | "repeat"), None) = (suedfn::resolve_command(repeated_name), &state.last_command) {
+            say!("nothing to repeat");
+            return ExitStatus::Success;
+        }
+        let repeated = format!("{}{}", state.prefix, arguments.trim_start());
+        for _ in 0..count {
+            if let ExitStatus::Failure = run_command(&repeated, buffer, state) {
+                return ExitStatus::Failure;
+            }
+        }
+        if !matches!(arguments.split_whitespace().next(), Some(".") | Some("repeat")) {
+            state.last_command = Some(command.to_string());
+        }
+        return ExitStatus::Success;
+    }
+
+    if let Some(expansion) = state.aliases.get(name).cloned() {
+        let expanded = match suedfn::expand_alias(&expansion, arguments.trim()) {
+            Ok(expanded) => expanded,
+            Err(e) => {
+                say!("{}", e);
+                return ExitStatus::Success;
+            }
+        };
+        if !enter_nested(state) {
+            return ExitStatus::Success;
+        }
+        let status = run_chain(&format!("{}{}", state.prefix, expanded), buffer, state, false);
+        state.depth -= 1;
+        state.last_command = Some(command.to_string());
+        return status;
+    }
+
+    if let suedfn::CommandName::Known("." | "repeat") = suedfn::resolve_command(name) {
+        let count = arguments.trim().parse::<usize>().unwrap_or(1);
+        match state.last_command.clone() {
+            Some(last_command) => {
+                for _ in 0..count {
+                    if let ExitStatus::Failure = run_command(&last_command, buffer, state) {
+                        return ExitStatus::Failure;
+                    }
+                }
+                state.last_command = Some(last_command);
+            }
+            None => say!("nothing to repeat"),
+        }
+        return ExitStatus::Success;
+    }
+
+    state.last_command = Some(command.to_string());
+    process_command(command, buffer, state)
+}
+
+/// Process an editing `command`, after splitting it into arguments with `suedfn::tokenize`.
+/// Requires mutable access to `buffer` and `state`, since this function will need to modify these.
 /// Related functions are available in `functions.rs`.
-fn process_command(command_args: Vec<&str>, buffer: &mut FileBuffer, prompt: &mut String, prefix: &mut String) -> ExitStatus {
-    match command_args[0].to_lowercase().replace(prefix.as_str(), "").as_str() {
+fn process_command(command: &str, buffer: &mut FileBuffer, state: &mut EditorState) -> ExitStatus {
+    let tokens = match suedfn::tokenize(command) {
+        Ok(tokens) => tokens,
+        Err(e) => {
+            say!("couldn't read that command, because of an {}", e);
+            return ExitStatus::Success;
+        }
+    };
+    let command_args: Vec<&str> = tokens.iter().map(String::as_str).collect();
+    if command_args.is_empty() {
+        return ExitStatus::Success;
+    }
+
+    let name = command_args[0].to_lowercase().replace(state.prefix.as_str(), "");
+    let resolved = suedfn::resolve_command(&name);
+    // Built-in commands come first, but a script or plugin's full name beats an abbreviation of a built-in command
+    if !matches!(resolved, suedfn::CommandName::Known(command_name) if command_name == name) {
+        if let Some(script_path) = scripting::find_script(&name) {
+            run_script_file(&script_path, &command_args[1..], buffer, state);
+            return ExitStatus::Success;
+        }
+        if let Some(plugin_path) = suedfn::find_plugin(&name).filter(|_| state.restricted.is_none()) {
+            run_plugin(&plugin_path, &command_args[1..], buffer, state);
+            return ExitStatus::Success;
+        }
+    }
+    let command_name = match resolved {
+        suedfn::CommandName::Known(command_name) => command_name,
+        suedfn::CommandName::Ambiguous(candidates) => {
+            say!("{} is ambiguous, it could be {}", name, suedfn::join_with_or(&candidates));
+            return ExitStatus::Success;
+        }
+        suedfn::CommandName::Unknown(suggestions) => {
+            if suggestions.is_empty() {
+                say!("{} is an unknown command", name);
+            }
+            else {
+                say!("{} is an unknown command, did you mean {}?", name, suedfn::join_with_or(&suggestions));
+            }
+            if state.interactive && !buffer.readonly && state.options.get("offer_text").is_some_and(OptionValue::as_bool) && suedfn::confirm("insert it as text instead?") {
+                buffer.contents.push(command.to_string());
+                buffer.cursor = buffer.contents.len();
+            }
+            return ExitStatus::Success;
+        }
+    };
+
+    if !state.interactive && INTERACTIVE_COMMANDS.contains(&command_name) {
+        say!("{} needs someone at the keyboard, so it can't be used here", command_name);
+        return ExitStatus::Success;
+    }
+    if state.restricted.is_some() && RESTRICTED_COMMANDS.contains(&command_name) {
+        say!("{} isn't allowed in restricted mode", command_name);
+        return ExitStatus::Success;
+    }
+    if buffer.readonly && MUTATING_COMMANDS.contains(&command_name) {
+        say!("this buffer is read-only, so {} can't be used on it, try ~readonly off", command_name);
+        return ExitStatus::Success;
+    }
+
+    match command_name {
         // Help commands
         "about" => { suedfn::about_sued(); },
-        "help" => { suedfn::extended_command_list(&prefix); },
+        "help" => { suedfn::extended_command_list(&state.prefix); },
+        "history" => {
+            if command_args.len() < 2 {
+                suedfn::show_history(&state.history);
+            }
+            else {
+                match command_args[1].parse::<usize>().ok().and_then(|number| state.history.get(number.wrapping_sub(1)).cloned()) {
+                    Some(entry) => {
+                        let entry_name = entry.strip_prefix(&state.prefix).and_then(|entry| entry.split_whitespace().next()).unwrap_or_default();
+                        // ~history N is the last command by now, so repeating the last command would run this again
+                        if let suedfn::CommandName::Known("history" | "." | "repeat") = suedfn::resolve_command(entry_name) {
+                            say!("that's a history or repeat command, running it would go round in circles");
+                        }
+                        else if enter_nested(state) {
+                            say!("{}", entry);
+                            let status = process_line(&entry, buffer, state);
+                            state.depth -= 1;
+                            return status;
+                        }
+                    }
+                    None => say!("no history entry {}", command_args[1]),
+                }
+            }
+        },
 
         // Buffer manipulation
         "clear" => { 
             buffer.contents.clear();
             buffer.file_path = None;
+            buffer.cursor = 0;
         },
         "copy" => {
             let end_point = buffer.contents.len();
+            let mode = clipboard_mode(state);
             if command_args.len() >= 2 {
                 let line_number = command_args[1];
-                suedfn::copy(&mut buffer.contents, suedfn::parse_tilde_range(line_number, end_point));
+                suedfn::copy(&buffer.contents, suedfn::parse_tilde_range(line_number, end_point, buffer.cursor), &mut state.clipboard, mode);
+            }
+            else {
+                suedfn::copy(&buffer.contents, (1, end_point), &mut state.clipboard, mode);
+            }
+        }
+        "clipboard" => {
+            if command_args.len() >= 2 {
+                match suedfn::ClipboardMode::from_name(command_args[1]) {
+                    Some(mode) => {
+                        state.options.set("clipboard", mode.name(), false).unwrap_or_default();
+                        say!("clipboard set to {}", mode.name());
+                    }
+                    None => say!("{} isn't a clipboard, try auto, native, osc52 or internal", command_args[1]),
+                }
             }
             else {
-                suedfn::copy(&mut buffer.contents, (1, end_point));
+                say!("clipboard is {}", clipboard_mode(state).name());
             }
         }
         "correct" => {
             let line_number = buffer.contents.len();
             suedfn::replace(&mut buffer.contents, line_number);
+            buffer.move_cursor(line_number);
         }
         "del" | "delete" => {
             if command_args.len() >= 2 {
-                let range = suedfn::parse_tilde_range(command_args[1], buffer.contents.len());
+                let range = suedfn::parse_tilde_range(command_args[1], buffer.contents.len(), buffer.cursor);
                 let start_point = range.0;
                 let end_point = range.1;
                 for line_number in start_point..end_point + 1 {
                     suedfn::delete(&mut buffer.contents, line_number);
                 }
+                buffer.move_cursor(start_point.min(buffer.contents.len()));
             }
             else {
-                println!("delete what?");
+                say!("delete what?");
             }
         }
         "indent" => {
             if command_args.len() >= 2 {
-                let range = suedfn::parse_tilde_range(command_args[1], buffer.contents.len());
+                let range = suedfn::parse_tilde_range(command_args[1], buffer.contents.len(), buffer.cursor);
                 let start_point = range.0;
                 let end_point = range.1;
-                if command_args.len() >= 3 {
-                    let indentation: isize = command_args[2].parse().unwrap_or(0);
-                    for line_number in start_point..end_point + 1 {
-                        suedfn::indent(&mut buffer.contents, line_number, indentation);
-                    }
-                }
-                else {
-                    if start_point == end_point {
-                        println!("indent line {} by how many spaces?", start_point);
-                    }
-                    else {
-                        println!("indent lines {} to {} by how many spaces?", start_point, end_point);
-                    }
+                let indentation: isize = match command_args.get(2) {
+                    Some(level) => level.parse().unwrap_or(0),
+                    None => buffer.option(&state.options, "tab_width").as_number() as isize,
+                };
+                let tab_width = buffer.option(&state.options, "tab_width").as_number();
+                let expand_tab = buffer.option(&state.options, "expand_tab").as_bool();
+                for line_number in start_point..end_point + 1 {
+                    suedfn::indent(&mut buffer.contents, line_number, indentation, tab_width, expand_tab);
                 }
+                buffer.move_cursor(end_point);
             }
             else {
-                println!("indent which line?");
+                say!("indent which line?");
             }
         },
         "insert" => {
             if command_args.len() >= 2 {
-                let line_number = command_args[1].parse::<usize>().unwrap_or(0);
+                let line_number = suedfn::parse_line_number(command_args[1], buffer.cursor).unwrap_or(0);
                 suedfn::insert(&mut buffer.contents, line_number);
+                buffer.move_cursor(line_number);
             }
             else {
-                println!("insert where?");
+                say!("insert where?");
             }
         },
         "open" => { 
             if command_args.len() >= 2 {
-                let file_name_with_spaces = command_args[1..].join(" ");
-                let expanded_file_path = tilde(&file_name_with_spaces).to_string();
-                buffer.contents = suedfn::open(expanded_file_path.as_str(), &mut buffer.file_path);
+                open_file(buffer, &command_args[1..].join(" "), state);
+            }
+            else {
+                say!("open what?");
+            }
+        },
+        "paste" => {
+            if command_args.len() >= 2 {
+                let line_number = suedfn::parse_line_number(command_args[1], buffer.cursor).unwrap_or(0);
+                suedfn::paste(&mut buffer.contents, Some(line_number), &state.clipboard);
+                buffer.move_cursor(line_number + state.clipboard.register.len().saturating_sub(1));
             }
             else {
-                println!("open what?");
+                suedfn::paste(&mut buffer.contents, None, &state.clipboard);
+                buffer.cursor = buffer.contents.len();
             }
         },
         "reopen" => {
             let file_path = buffer.file_path.clone().unwrap_or_default();
             buffer.contents = suedfn::open(file_path.as_str(), &mut buffer.file_path);
+            buffer.cursor = buffer.contents.len();
+            detect_line_ending(buffer, &file_path);
         },
         "replace" => {
             if command_args.len() >= 2 {
-                let line_number = command_args[1].parse::<usize>().unwrap_or(0);
+                let line_number = suedfn::parse_line_number(command_args[1], buffer.cursor).unwrap_or(0);
                 suedfn::replace(&mut buffer.contents, line_number);
+                buffer.move_cursor(line_number);
             }
             else {
-                println!("replace which line?");
+                say!("replace which line?");
             }
         },
         "save" => {
@@ -180,50 +743,77 @@ fn process_command(command_args: Vec<&str>, buffer: &mut FileBuffer, prompt: &mu
                 destination = command_args[1..].join(" ");
             }
 
-            let expanded_file_path: String = tilde(&destination).to_string();
-
-            if !expanded_file_path.trim().is_empty() {
-                suedfn::save(&buffer.contents, expanded_file_path.as_str());
-                buffer.file_path = Some(destination);
+            if destination.trim().is_empty() {
+                say!("save where?");
             }
             else {
-                println!("save where?");
+                match resolve_path(&destination, state) {
+                    Ok(expanded_file_path) => {
+                        suedfn::save(&buffer.contents, expanded_file_path.as_str(), save_options(buffer, state));
+                        buffer.file_path = Some(destination);
+                    }
+                    Err(e) => say!("{}", e),
+                }
             }
         },
         "sub" | "substitute" => {
             if command_args.len() >= 3 {
-                let line_number = command_args[1].parse::<usize>().unwrap_or(0);
-                let combined_args = command_args[2..].join(" ");
-                let pattern_replacement = suedfn::split_pattern_replacement(combined_args.as_str());
+                let line_number = suedfn::parse_line_number(command_args[1], buffer.cursor).unwrap_or(0);
+                // Everything after the line number is the pattern and replacement, with quotes and escapes
+                // worked out like any other argument, but without splitting it up, so runs of spaces survive
+                let combined_args = match suedfn::unquote(raw_arguments(raw_arguments(command))) {
+                    Ok(combined_args) => combined_args,
+                    Err(e) => {
+                        say!("{}", e);
+                        return ExitStatus::Success;
+                    }
+                };
+                let pattern_replacement = suedfn::split_pattern_replacement(&combined_args);
                 if pattern_replacement.len() >= 2 {
                     let pattern = pattern_replacement[0];
                     let replacement = pattern_replacement[1];
                     suedfn::substitute(&mut buffer.contents, line_number, pattern, replacement);
+                    buffer.move_cursor(line_number);
                 }
                 else {
-                    println!("substitute what?");
-                    println!("try substitute line pattern/replacement");
+                    say!("substitute what?");
+                    say!("try substitute line pattern/replacement");
                 }
             }
             else if command_args.len() >= 2 {
-                println!("substitute what?");
-                println!("try substitute line pattern/replacement");
+                say!("substitute what?");
+                say!("try substitute line pattern/replacement");
             }
             else {
-                println!("substitute which line?");
+                say!("substitute which line?");
             }
         }
+        "retab" => {
+            if command_args.len() >= 2 {
+                let (start_point, end_point) = suedfn::parse_tilde_range(command_args[1], buffer.contents.len(), buffer.cursor);
+                let tab_width = buffer.option(&state.options, "tab_width").as_number();
+                let expand_tab = buffer.option(&state.options, "expand_tab").as_bool();
+                for line_number in start_point..end_point + 1 {
+                    suedfn::retab(&mut buffer.contents, line_number, tab_width, expand_tab);
+                }
+                buffer.move_cursor(end_point);
+            }
+            else {
+                say!("retab which lines?");
+            }
+        },
         "swap" => {
             if command_args.len() >= 3 {
-                let source_line = command_args[1].parse::<usize>().unwrap_or(0);
-                let target_line = command_args[2].parse::<usize>().unwrap_or(0);
+                let source_line = suedfn::parse_line_number(command_args[1], buffer.cursor).unwrap_or(0);
+                let target_line = suedfn::parse_line_number(command_args[2], buffer.cursor).unwrap_or(0);
                 suedfn::swap(&mut buffer.contents, source_line, target_line);
+                buffer.move_cursor(target_line);
             }
             else if command_args.len() >= 2 {
-                println!("swap line {} with what?", command_args[1]);
+                say!("swap line {} with what?", command_args[1]);
             }
             else {
-                println!("swap which lines?");
+                say!("swap which lines?");
             }
         },
         "write" => {
@@ -233,24 +823,49 @@ fn process_command(command_args: Vec<&str>, buffer: &mut FileBuffer, prompt: &mu
                 destination = command_args[1..].join(" ");
             }
 
-            let expanded_file_path: String = tilde(&destination).to_string();
-
-            if !expanded_file_path.trim().is_empty() {
-                suedfn::save(&buffer.contents, expanded_file_path.as_str());
+            if destination.trim().is_empty() {
+                say!("write where?");
             }
             else {
-                println!("write where?");
+                match resolve_path(&destination, state) {
+                    Ok(expanded_file_path) => suedfn::save(&buffer.contents, expanded_file_path.as_str(), save_options(buffer, state)),
+                    Err(e) => say!("{}", e),
+                }
             }
         },
 
         // Informational commands
         "search" => {
-            if command_args.len() >= 2 {
-                let term = command_args[1..].join(" ");
-                suedfn::search(&buffer.contents, &term);
+            match suedfn::parse_search_context(&command_args[1..]) {
+                Ok((context, term_start)) if term_start + 1 < command_args.len() => {
+                    let term = command_args[term_start + 1..].join(" ");
+                    suedfn::search(&buffer.contents, &term, context, &show_options(buffer, state));
+                }
+                Ok(_) => say!("search for what?"),
+                Err(e) => say!("{}", e),
             }
-            else {
-                println!("search for what?");
+        },
+        "around" => {
+            let line_number = match command_args.get(1) {
+                Some(specifier) => suedfn::parse_line_number(specifier, buffer.cursor).unwrap_or(0),
+                None => buffer.cursor,
+            };
+            match command_args.get(2).map_or(Ok(3), |context| context.parse::<usize>()) {
+                Ok(context) => {
+                    buffer.move_cursor(line_number);
+                    suedfn::around(&buffer.contents, line_number, context, &show_options(buffer, state));
+                }
+                Err(_) => say!("{} isn't a number of lines", command_args[2]),
+            }
+        },
+        "what" => {
+            let line_number = match command_args.get(1) {
+                Some(specifier) => suedfn::parse_line_number(specifier, buffer.cursor).unwrap_or(0),
+                None => buffer.cursor,
+            };
+            match command_args.get(2).map(|column| column.parse::<usize>()) {
+                Some(Err(_)) => say!("{} isn't a column", command_args[2]),
+                column => suedfn::what(&buffer.contents, line_number, column.and_then(Result::ok)),
             }
         },
         "print" => {
@@ -258,65 +873,596 @@ fn process_command(command_args: Vec<&str>, buffer: &mut FileBuffer, prompt: &mu
             let mut end_point = buffer.contents.len();
 
             if command_args.len() >= 2 {
-                let range = suedfn::parse_tilde_range(command_args[1], buffer.contents.len());
+                let range = suedfn::parse_tilde_range(command_args[1], buffer.contents.len(), buffer.cursor);
 
                 start_point = range.0;
                 end_point = range.1;
             }
 
-            suedfn::show(&buffer.contents, start_point, end_point, false);
+            let show_options = suedfn::ShowOptions {
+                line_numbers: false,
+                numbering: suedfn::Numbering::Absolute,
+                cursor: buffer.cursor,
+                gutter: String::new(),
+                colour: false,
+                wrap: false,
+                language: None,
+                list: false,
+                tab_width: 0,
+                pager: pager_setting(state),
+            };
+            suedfn::show(&buffer.contents, start_point, end_point, &show_options);
+            buffer.move_cursor(end_point);
         },
         "show" => {
             let mut start_point = 1;
             let mut end_point = buffer.contents.len();
 
             if command_args.len() >= 2 {
-                let range = suedfn::parse_tilde_range(command_args[1], buffer.contents.len());
+                let range = suedfn::parse_tilde_range(command_args[1], buffer.contents.len(), buffer.cursor);
 
                 start_point = range.0;
                 end_point = range.1;
             }
 
-            suedfn::show(&buffer.contents, start_point, end_point, true);
+            suedfn::show(&buffer.contents, start_point, end_point, &show_options(buffer, state));
+            buffer.move_cursor(end_point);
         },
         
         // Miscellaneous commands
         "bsod" => { suedfn::crash("USER_IS_STUPID", &[0x0000DEAD, 0x00000101, 0xFFFFFFFF, 56]); },
         "prefix" => {
-            prefix.clear();
+            state.prefix.clear();
             if command_args.len() < 2 {
-                prefix.push_str("~");
-                println!("prefix reset to ~, try passing a prefix if you wanted that instead");
+                state.prefix.push('~');
+                say!("prefix reset to ~, try passing a prefix if you wanted that instead");
             }
             else {
                 let new_prefix = command_args[1];
-                prefix.push_str(new_prefix);
+                state.prefix.push_str(new_prefix);
+            }
+        }
+        "set" | "setlocal" => {
+            let local = command_name == "setlocal";
+            if command_args.len() < 2 {
+                list_options(buffer, state, local);
+            }
+            else if command_args.len() < 3 {
+                match options::find_option(command_args[1]) {
+                    Some(spec) => say!("{} is {} - {}", spec.name, buffer.option(&state.options, spec.name), spec.description),
+                    None => say!("{} isn't an option", command_args[1]),
+                }
+            }
+            else {
+                let value = command_args[2..].join(" ");
+                let result = if local && value == "default" {
+                    buffer.options.unset(command_args[1]);
+                    Ok(command_args[1].to_string())
+                }
+                else if local {
+                    buffer.options.set(command_args[1], &value, true).map(String::from)
+                }
+                else {
+                    state.options.set(command_args[1], &value, false).map(String::from)
+                };
+                match result {
+                    Ok(name) => say!("{} is {}", name, buffer.option(&state.options, &name)),
+                    Err(e) => say!("{}", e),
+                }
+            }
+        }
+        "alias" => {
+            let arguments = raw_arguments(command);
+            let (alias_name, expansion) = match arguments.split_once(char::is_whitespace) {
+                Some((alias_name, expansion)) => (alias_name, expansion.trim()),
+                None => (arguments, ""),
+            };
+            match (alias_name, expansion) {
+                ("", _) if state.aliases.is_empty() => say!("no aliases yet, try ~alias name expansion"),
+                ("", _) => {
+                    for (alias_name, expansion) in &state.aliases {
+                        say!("{} = {}", alias_name, expansion);
+                    }
+                }
+                ("save", alias_name) if !alias_name.is_empty() => match state.aliases.get(alias_name) {
+                    Some(expansion) => update_config("aliases", alias_name, Some(expansion.as_str().into()), state),
+                    None => say!("no alias called {}", alias_name),
+                },
+                ("delete", alias_name) if !alias_name.is_empty() => match state.aliases.remove(alias_name) {
+                    Some(_) => {
+                        update_config("aliases", alias_name, None, state);
+                        say!("alias {} deleted", alias_name);
+                    }
+                    None => say!("no alias called {}", alias_name),
+                },
+                (alias_name, "") => match state.aliases.get(alias_name) {
+                    Some(expansion) => say!("{} = {}", alias_name, expansion),
+                    None => say!("no alias called {}, try ~alias {} expansion to make one", alias_name, alias_name),
+                },
+                (alias_name, expansion) => {
+                    match check_alias_name(alias_name) {
+                        Err(e) => say!("{}", e),
+                        Ok(()) => {
+                            state.aliases.insert(alias_name.to_string(), expansion.to_string());
+                            say!("{} = {}", alias_name, expansion);
+                        }
+                    }
+                }
+            }
+        }
+        "macro" => {
+            let macro_name = command_args.get(2).copied();
+            match (command_args.get(1).copied(), macro_name) {
+                (None, _) if state.macros.is_empty() => say!("no macros yet, try ~macro record name"),
+                (None, _) => {
+                    for (macro_name, lines) in &state.macros {
+                        say!("{} ({} lines)", macro_name, lines.len());
+                    }
+                }
+                (Some("record"), Some(macro_name)) => {
+                    if let Some((recording, _)) = &state.recording {
+                        say!("already recording {}, use ~macro stop first", recording);
+                    }
+                    else if let Err(e) = check_name(macro_name) {
+                        say!("{}", e);
+                    }
+                    else {
+                        state.recording = Some((macro_name.to_string(), Vec::new()));
+                        say!("recording macro {}, use ~macro stop when you're done", macro_name);
+                    }
+                }
+                (Some("stop"), _) => match state.recording.take() {
+                    Some((_, lines)) if lines.is_empty() => say!("nothing recorded - no macro made"),
+                    Some((macro_name, lines)) => {
+                        say!("recorded macro {} with {} lines", macro_name, lines.len());
+                        state.macros.insert(macro_name, lines);
+                    }
+                    None => say!("not recording a macro"),
+                },
+                (Some("run"), Some(macro_name)) => {
+                    let count = command_args.get(3).and_then(|count| count.parse::<usize>().ok()).unwrap_or(1);
+                    match state.macros.get(macro_name).cloned() {
+                        Some(lines) => {
+                            if !enter_nested(state) {
+                                return ExitStatus::Success;
+                            }
+                            for line in lines.iter().cycle().take(lines.len() * count) {
+                                if let ExitStatus::Failure = process_line(line, buffer, state) {
+                                    state.depth -= 1;
+                                    return ExitStatus::Failure;
+                                }
+                            }
+                            state.depth -= 1;
+                            state.last_command = Some(command.to_string());
+                        }
+                        None => say!("no macro called {}", macro_name),
+                    }
+                }
+                (Some("show"), Some(macro_name)) => match state.macros.get(macro_name) {
+                    Some(lines) => suedfn::show(lines, 1, lines.len(), &suedfn::ShowOptions { line_numbers: true, language: None, ..show_options(buffer, state) }),
+                    None => say!("no macro called {}", macro_name),
+                },
+                (Some("save"), Some(macro_name)) => match state.macros.get(macro_name) {
+                    Some(lines) => update_config("macros", macro_name, Some(lines.iter().collect::<toml_edit::Array>().into()), state),
+                    None => say!("no macro called {}", macro_name),
+                },
+                (Some("delete"), Some(macro_name)) => match state.macros.remove(macro_name) {
+                    Some(_) => {
+                        update_config("macros", macro_name, None, state);
+                        say!("macro {} deleted", macro_name);
+                    }
+                    None => say!("no macro called {}", macro_name),
+                },
+                (Some("record" | "run" | "show" | "save" | "delete"), None) => say!("which macro?"),
+                (Some(subcommand), _) => say!("~macro can't {}, try record, stop, run, show, save or delete", subcommand),
+            }
+        }
+        "script" => {
+            if command_args.len() >= 2 {
+                match resolve_path(command_args[1], state) {
+                    Ok(script_path) => run_script_file(Path::new(&script_path), &command_args[2..], buffer, state),
+                    Err(e) => say!("{}", e),
+                }
+            }
+            else {
+                say!("run which script?");
+            }
+        }
+        "eval" => {
+            let expression = raw_arguments(command);
+            if expression.is_empty() {
+                say!("evaluate what?");
+            }
+            else if let Some(result) = run_script(expression, &[], buffer, state) {
+                if !result.is_unit() {
+                    say!("{}", result);
+                }
+            }
+        }
+        "buffer" => {
+            let buffer_count = state.buffers.len();
+            match command_args.get(1).copied() {
+                None => list_buffers(buffer, state),
+                Some("next") => switch_buffer((state.current_buffer + 1) % buffer_count, buffer, state),
+                Some("previous") => switch_buffer((state.current_buffer + buffer_count - 1) % buffer_count, buffer, state),
+                Some("new") => {
+                    state.buffers.push(FileBuffer::new());
+                    switch_buffer(buffer_count, buffer, state);
+                    if command_args.len() >= 3 {
+                        open_file(buffer, &command_args[2..].join(" "), state);
+                    }
+                }
+                Some("close") if buffer_count == 1 => say!("that's the only buffer, try ~clear instead"),
+                Some("close") => {
+                    state.buffers.remove(state.current_buffer);
+                    state.current_buffer = state.current_buffer.min(state.buffers.len() - 1);
+                    *buffer = std::mem::replace(&mut state.buffers[state.current_buffer], FileBuffer::new());
+                    say!("buffer closed, now on buffer {} - {}", state.current_buffer + 1, buffer_name(buffer));
+                }
+                Some(number) => match number.parse::<usize>() {
+                    Ok(number) if (1..=buffer_count).contains(&number) => switch_buffer(number - 1, buffer, state),
+                    _ => say!("no buffer {}, try ~buffer to list them", number),
+                },
+            }
+        }
+        "readonly" => {
+            match command_args.get(1).copied() {
+                None => say!("this buffer is {}", if buffer.readonly { "read-only" } else { "writable" }),
+                Some("on") => {
+                    buffer.readonly = true;
+                    say!("this buffer is read-only now");
+                }
+                Some("off") => {
+                    buffer.readonly = false;
+                    say!("this buffer can be changed again");
+                }
+                Some(other) => say!("{} isn't on or off", other),
             }
         }
         "prompt" => {
-            prompt.clear();
+            state.prompt.clear();
             if command_args.len() < 2 {
-                println!("prompt reset, try passing a prompt if you wanted that instead");
+                say!("prompt reset, try passing a prompt if you wanted that instead");
             }
             else {
                 let new_prompt = format!("{} ", command_args[1..].join(" "));
-                prompt.push_str(&new_prompt);
+                state.prompt.push_str(&new_prompt);
             }
         }
-        "run"  => { suedfn::shell_command(command_args.clone()); },
+        "run"  => { suedfn::shell_command(raw_arguments(command)); },
         "runhere" => { 
-            let command_args_string = command_args.iter().map(|&s| s.to_string()).collect();
-            suedfn::shell_command_with_file(command_args_string, &mut buffer.contents, buffer.file_path.clone());
+            suedfn::shell_command_with_file(raw_arguments(command), &mut buffer.contents, buffer.file_path.clone());
         }
         "nothing" => { suedfn::nothing(&buffer.contents); },
 
         // Exit command
         "exit" | "quit" => return ExitStatus::Failure,
 
-        // Fallback
+        // Fallback, for commands `resolve_command` knows about that don't do anything here, like `~repeat`
         _ => { 
-            println!("{} is an unknown command", command_args[0].replace(prefix.as_str(), ""));
+            say!("{} can't be used here", command_name);
         }
     };
+    buffer.cursor = buffer.cursor.min(buffer.contents.len());
     ExitStatus::Success
 }
+
+/// Runs the plugin at `plugin_path` on the buffer, for commands sued doesn't have.
+/// If the first argument is a line number or range, the plugin only gets those lines, otherwise it gets the whole buffer.
+/// The plugin also gets the file path, cursor and range in `SUED_FILE`, `SUED_CURSOR`, `SUED_START` and `SUED_END`.
+fn run_plugin(plugin_path: &std::path::Path, arguments: &[&str], buffer: &mut FileBuffer, state: &EditorState) {
+    let is_range = |argument: &str| !argument.is_empty() && argument.chars().all(|c| c.is_ascii_digit() || c == '~' || c == '.');
+    let (range, arguments) = match arguments.split_first() {
+        Some((first, rest)) if is_range(first) => (suedfn::parse_tilde_range(first, buffer.contents.len(), buffer.cursor), rest),
+        _ => ((1, buffer.contents.len()), arguments),
+    };
+    let (start_point, end_point) = range;
+    if !buffer.contents.is_empty() && (start_point < 1 || start_point > end_point || end_point > buffer.contents.len()) {
+        say!("lines {} to {} aren't all in the buffer", start_point, end_point);
+        return;
+    }
+    let lines = buffer.contents.get(start_point.saturating_sub(1)..end_point).unwrap_or_default();
+
+    let environment = [
+        ("SUED_FILE", buffer.file_path.clone().unwrap_or_default()),
+        ("SUED_CURSOR", buffer.cursor.to_string()),
+        ("SUED_START", start_point.to_string()),
+        ("SUED_END", end_point.to_string()),
+        ("SUED_LINES", buffer.contents.len().to_string()),
+        ("SUED_PREFIX", state.prefix.clone()),
+    ];
+    match suedfn::run_plugin(plugin_path, arguments, lines, &environment) {
+        Ok((suedfn::PluginMode::Replace | suedfn::PluginMode::Insert, _)) if buffer.readonly => {
+            say!("this buffer is read-only, so the plugin's output was thrown away, try ~readonly off");
+        }
+        Ok((suedfn::PluginMode::Replace, output)) => {
+            let replaced = start_point.saturating_sub(1)..end_point.min(buffer.contents.len());
+            let inserted = output.len();
+            buffer.contents.splice(replaced.clone(), output);
+            buffer.cursor = (replaced.start + inserted).min(buffer.contents.len());
+        }
+        Ok((suedfn::PluginMode::Insert, output)) => {
+            let position = end_point.min(buffer.contents.len());
+            let inserted = output.len();
+            buffer.contents.splice(position..position, output);
+            buffer.cursor = (position + inserted).min(buffer.contents.len());
+        }
+        Ok((suedfn::PluginMode::Print, output)) => {
+            for line in output {
+                say!("{}", line);
+            }
+        }
+        Err(e) => say!("{}", e),
+    }
+}
+
+/// Runs the Rhai `script` on the buffer, with `args` as its arguments.
+/// The buffer is only changed if the script finishes without an error.
+/// Returns whatever the script evaluated to, if it worked.
+fn run_script(script: &str, args: &[&str], buffer: &mut FileBuffer, state: &EditorState) -> Option<rhai::Dynamic> {
+    let script_buffer = scripting::ScriptBuffer {
+        lines: buffer.contents.clone(),
+        cursor: buffer.cursor,
+        file_path: buffer.file_path.clone(),
+        tab_width: buffer.option(&state.options, "tab_width").as_number(),
+        expand_tab: buffer.option(&state.options, "expand_tab").as_bool(),
+    };
+    match scripting::run_script(script, script_buffer, args) {
+        Ok((script_buffer, _)) if buffer.readonly && script_buffer.lines != buffer.contents => {
+            say!("this buffer is read-only, so the script's changes were thrown away, try ~readonly off");
+            None
+        }
+        Ok((script_buffer, result)) => {
+            buffer.contents = script_buffer.lines;
+            buffer.cursor = script_buffer.cursor.min(buffer.contents.len());
+            Some(result)
+        }
+        Err(e) => {
+            say!("script stopped, because {}", e);
+            None
+        }
+    }
+}
+
+/// Reads the script at `script_path` and runs it, for `~script` and script commands.
+fn run_script_file(script_path: &std::path::Path, args: &[&str], buffer: &mut FileBuffer, state: &EditorState) {
+    match std::fs::read_to_string(script_path) {
+        Ok(script) => {
+            run_script(&script, args, buffer, state);
+        }
+        Err(e) => say!("couldn't read {}, because {}", script_path.display(), e),
+    }
+}
+
+/// Opens the file at `file_path` in the `buffer`, replacing whatever was in it.
+/// Used by `~open` and `~buffer new`.
+fn open_file(buffer: &mut FileBuffer, file_path: &str, state: &EditorState) {
+    let expanded_file_path = match resolve_path(file_path, state) {
+        Ok(expanded_file_path) => expanded_file_path,
+        Err(e) => {
+            say!("{}", e);
+            return;
+        }
+    };
+    buffer.contents = suedfn::open(expanded_file_path.as_str(), &mut buffer.file_path);
+    buffer.cursor = buffer.contents.len();
+    buffer.options = Options::empty();
+    detect_line_ending(buffer, &expanded_file_path);
+}
+
+/// Expands a leading `~` in a `file_path` typed into a command.
+/// In restricted mode, `~` is left alone, and the file has to be inside the root directory instead.
+fn resolve_path(file_path: &str, state: &EditorState) -> Result<String, String> {
+    match &state.restricted {
+        None => Ok(tilde(file_path).to_string()),
+        Some(root) => suedfn::confine_path(file_path, root).map(|_| file_path.to_string()),
+    }
+}
+
+/// Moves into the `root` directory for restricted mode, or stays put if there isn't one,
+/// so relative paths start from the root. Returns the root's canonical path, for `suedfn::confine_path`.
+fn restricted_root(root: Option<&str>) -> Result<PathBuf, String> {
+    if let Some(root) = root {
+        env::set_current_dir(root).map_err(|e| format!("couldn't use {} as the root, because {}", root, e))?;
+    }
+    env::current_dir()
+        .and_then(|root| root.canonicalize())
+        .map_err(|e| format!("couldn't find the current directory, because {}", e))
+}
+
+/// Returns the `pager` option, except in restricted mode, where only the built-in pager is allowed,
+/// since most pagers can run shell commands.
+fn pager_setting(state: &EditorState) -> String {
+    match state.options.get("pager").map(OptionValue::as_text) {
+        Some("off") => String::from("off"),
+        _ if state.restricted.is_some() => String::from("builtin"),
+        Some(pager) => pager.to_string(),
+        None => String::from("auto"),
+    }
+}
+
+/// Returns what to call the `buffer` in messages, which is its file path if it has one.
+fn buffer_name(buffer: &FileBuffer) -> String {
+    buffer.file_path.clone().unwrap_or_else(|| String::from("no file"))
+}
+
+/// Lists every buffer for `~buffer`, marking the current one with a `*`.
+fn list_buffers(buffer: &FileBuffer, state: &EditorState) {
+    for (index, listed_buffer) in state.buffers.iter().enumerate() {
+        // The current buffer's slot is just a placeholder, so list the real one instead
+        let (marker, listed_buffer) = if index == state.current_buffer { ("*", buffer) } else { (" ", listed_buffer) };
+        say!("{} {} - {} ({} lines){}",
+            marker,
+            index + 1,
+            buffer_name(listed_buffer),
+            listed_buffer.contents.len(),
+            if listed_buffer.readonly { ", read-only" } else { "" });
+    }
+}
+
+/// Switches to the buffer at `index`, putting the current `buffer` back in `state.buffers` first.
+fn switch_buffer(index: usize, buffer: &mut FileBuffer, state: &mut EditorState) {
+    if index == state.current_buffer {
+        say!("already on buffer {}", index + 1);
+        return;
+    }
+    std::mem::swap(buffer, &mut state.buffers[state.current_buffer]);
+    std::mem::swap(buffer, &mut state.buffers[index]);
+    state.current_buffer = index;
+    say!("buffer {} - {}", index + 1, buffer_name(buffer));
+}
+
+/// Goes one alias, macro or history entry deeper, unless that's too deep.
+/// Returns whether it went deeper, and if it did, `state.depth` has to be decremented again afterwards.
+fn enter_nested(state: &mut EditorState) -> bool {
+    if state.depth >= MAX_DEPTH {
+        say!("gave up after {} aliases, macros and history entries deep, is one of them running itself?", MAX_DEPTH);
+        return false;
+    }
+    state.depth += 1;
+    true
+}
+
+/// Checks that `name` can be used for an alias or macro.
+/// Names are letters, numbers, `-` and `_`, and can't start with a number, since that's a count.
+fn check_name(name: &str) -> Result<(), String> {
+    if name.is_empty() {
+        Err(String::from("it needs a name"))
+    }
+    else if !name.chars().all(|c| c.is_alphanumeric() || c == '-' || c == '_') {
+        Err(format!("{} can only have letters, numbers, - and _ in it", name))
+    }
+    else if name.starts_with(|c: char| c.is_ascii_digit()) {
+        Err(format!("{} can't start with a number", name))
+    }
+    else {
+        Ok(())
+    }
+}
+
+/// Checks that `name` can be used for an alias, which is the same as `check_name`,
+/// except it can't be a built-in command either, since aliases are looked up first and would replace it.
+fn check_alias_name(name: &str) -> Result<(), String> {
+    check_name(name)?;
+    match suedfn::resolve_command(name) {
+        suedfn::CommandName::Known(builtin) if builtin == name => Err(format!("{} is already a command", name)),
+        _ => Ok(()),
+    }
+}
+
+/// Keeps an alias or macro in the `table` of the config file, or takes it out if `value` is `None`.
+fn update_config(table: &str, name: &str, value: Option<toml_edit::Value>, state: &EditorState) {
+    if state.restricted.is_some() {
+        say!("the config file can't be changed in restricted mode");
+        return;
+    }
+    let Some(config_path) = suedfn::config_file_path() else {
+        say!("there's nowhere to put a config file on this system");
+        return;
+    };
+    let saving = value.is_some();
+    match suedfn::update_config(&config_path, table, name, value) {
+        Ok(()) if saving => say!("{} saved to {}", name, config_path.display()),
+        Ok(()) => (),
+        Err(e) => say!("{}", e),
+    }
+}
+
+/// Lists options for the `~set` and `~setlocal` commands.
+/// `~set` lists every option, marking the ones this buffer overrides, and `~setlocal` lists just the overrides.
+fn list_options(buffer: &FileBuffer, state: &EditorState, local: bool) {
+    if local && buffer.options.is_empty() {
+        say!("no local options set for this buffer");
+        return;
+    }
+    for spec in options::OPTIONS.iter() {
+        let local_value = buffer.options.get(spec.name);
+        match (local, local_value, state.options.get(spec.name)) {
+            (true, Some(local_value), _) => say!("{} = {}", spec.name, local_value),
+            (false, Some(local_value), Some(value)) => say!("{} = {} ({} in this buffer)", spec.name, value, local_value),
+            (false, None, Some(value)) => say!("{} = {}", spec.name, value),
+            _ => (),
+        }
+    }
+}
+
+/// Returns the clipboard `~copy` should use, according to the `clipboard` option.
+fn clipboard_mode(state: &EditorState) -> suedfn::ClipboardMode {
+    let mode = state.options.get("clipboard").map_or("auto", OptionValue::as_text);
+    suedfn::ClipboardMode::from_name(mode).unwrap_or(suedfn::ClipboardMode::Auto)
+}
+
+/// Returns how `~save` and `~write` should write out the `buffer`, according to its options.
+fn save_options(buffer: &FileBuffer, state: &EditorState) -> suedfn::SaveOptions {
+    suedfn::SaveOptions {
+        backup: buffer.option(&state.options, "backup").as_bool(),
+        crlf: buffer.option(&state.options, "line_ending").as_text() == "crlf",
+    }
+}
+
+/// Returns the language the `buffer` is in, according to its `syntax` option.
+fn buffer_language(buffer: &FileBuffer, state: &EditorState) -> Option<highlight::Language> {
+    match buffer.option(&state.options, "syntax").as_text() {
+        "auto" => highlight::detect_language(buffer.file_path.as_deref(), buffer.contents.first().map(String::as_str)),
+        language => highlight::Language::from_name(language),
+    }
+}
+
+/// Returns the indentation to put in front of the next typed line, which is nothing unless the `auto_indent` option is on.
+fn auto_indentation(buffer: &FileBuffer, state: &EditorState) -> String {
+    match buffer.contents.last() {
+        Some(previous_line) if !buffer.readonly && buffer.option(&state.options, "auto_indent").as_bool() => {
+            let tab_width = buffer.option(&state.options, "tab_width").as_number();
+            let expand_tab = buffer.option(&state.options, "expand_tab").as_bool();
+            suedfn::next_indentation(previous_line, buffer_language(buffer, state), tab_width, expand_tab)
+        }
+        _ => String::new(),
+    }
+}
+
+/// Tidies up a typed `line` that `auto_indentation` put `indentation` in front of.
+/// Commands lose the indentation again, and lines that close a block go out a level.
+/// Escaped lines like `\~text` are typed text, so they keep it.
+fn settle_typed_line(line: String, indentation: &str, buffer: &FileBuffer, state: &EditorState) -> String {
+    if indentation.is_empty() {
+        return line;
+    }
+    let text = line.trim_start();
+    if text.starts_with(&state.prefix) {
+        return text.to_string();
+    }
+    let tab_width = buffer.option(&state.options, "tab_width").as_number();
+    suedfn::settle_indentation(&line, indentation, buffer_language(buffer, state), tab_width)
+}
+
+/// Returns how `~show` should display the `buffer`, according to its options.
+fn show_options(buffer: &FileBuffer, state: &EditorState) -> suedfn::ShowOptions {
+    suedfn::ShowOptions {
+        line_numbers: buffer.option(&state.options, "line_numbers").as_bool(),
+        numbering: match buffer.option(&state.options, "numbering").as_text() {
+            "relative" => suedfn::Numbering::Relative,
+            "hybrid" => suedfn::Numbering::Hybrid,
+            _ => suedfn::Numbering::Absolute,
+        },
+        cursor: buffer.cursor,
+        gutter: match buffer.option(&state.options, "gutter").as_text() {
+            "none" => String::new(),
+            gutter => gutter.to_string(),
+        },
+        colour: suedfn::colour_enabled(buffer.option(&state.options, "colour").as_bool()),
+        wrap: buffer.option(&state.options, "wrap").as_bool(),
+        language: buffer_language(buffer, state),
+        list: buffer.option(&state.options, "list").as_bool(),
+        tab_width: buffer.option(&state.options, "tab_width").as_number(),
+        pager: pager_setting(state),
+    }
+}
+
+/// Returns everything in `command` after the command name, exactly as it was typed.
+/// Used by commands that hand their arguments to something with its own quoting rules, like the shell.
+fn raw_arguments(command: &str) -> &str {
+    match command.trim_start().split_once(char::is_whitespace) {
+        Some((_, arguments)) => arguments.trim_start(),
+        None => "",
+    }
+}
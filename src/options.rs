@@ -0,0 +1,286 @@
+//! Contains sued's editor options, which are changed with `~set`, `~setlocal` and the config file.
+//!
+//! This file is part of sued.
+//!
+//! Visit `main.rs` for context and usage.
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// The type of value an option holds, which decides what `~set` accepts for it.
+pub enum OptionKind {
+    Bool,
+    Number,
+    Choice(&'static [&'static str]),
+    Text,
+}
+
+/// Describes an option that sued knows about.
+/// `local` options can be set per buffer with `~setlocal`, the rest only apply to the whole editor.
+pub struct OptionSpec {
+    pub name: &'static str,
+    pub kind: OptionKind,
+    pub default: &'static str,
+    pub local: bool,
+    pub description: &'static str,
+}
+
+/// Every option sued knows about, in the order `~set` lists them.
+pub const OPTIONS: [OptionSpec; 19] = [
+    OptionSpec {
+        name: "auto_indent",
+        kind: OptionKind::Bool,
+        default: "false",
+        local: true,
+        description: "start typed lines with the last line's indentation, going in and out a level around blocks",
+    },
+    OptionSpec {
+        name: "backup",
+        kind: OptionKind::Bool,
+        default: "false",
+        local: false,
+        description: "keep the old version of a file as file~ when saving over it",
+    },
+    OptionSpec {
+        name: "clipboard",
+        kind: OptionKind::Choice(&["auto", "native", "osc52", "internal"]),
+        default: "auto",
+        local: false,
+        description: "where ~copy copies to",
+    },
+    OptionSpec {
+        name: "colour",
+        kind: OptionKind::Bool,
+        default: "true",
+        local: false,
+        description: "use colour in the output, unless NO_COLOR is set",
+    },
+    OptionSpec {
+        name: "expand_tab",
+        kind: OptionKind::Bool,
+        default: "true",
+        local: true,
+        description: "indent with spaces instead of tabs",
+    },
+    OptionSpec {
+        name: "gutter",
+        kind: OptionKind::Text,
+        default: "│",
+        local: true,
+        description: "what goes between the line numbers and the text in ~show, none for just a space",
+    },
+    OptionSpec {
+        name: "history_size",
+        kind: OptionKind::Number,
+        default: "1000",
+        local: false,
+        description: "how many history entries to keep",
+    },
+    OptionSpec {
+        name: "history_text",
+        kind: OptionKind::Bool,
+        default: "true",
+        local: false,
+        description: "keep typed text in history, not just commands",
+    },
+    OptionSpec {
+        name: "line_ending",
+        kind: OptionKind::Choice(&["lf", "crlf"]),
+        default: "lf",
+        local: true,
+        description: "the line ending files are saved with",
+    },
+    OptionSpec {
+        name: "line_numbers",
+        kind: OptionKind::Bool,
+        default: "true",
+        local: true,
+        description: "show line numbers in ~show",
+    },
+    OptionSpec {
+        name: "list",
+        kind: OptionKind::Bool,
+        default: "false",
+        local: true,
+        description: "show tabs, trailing spaces and other invisible characters in ~show",
+    },
+    OptionSpec {
+        name: "numbering",
+        kind: OptionKind::Choice(&["absolute", "relative", "hybrid"]),
+        default: "absolute",
+        local: true,
+        description: "number lines in ~show from the start, from the current line, or from the current line except the current line",
+    },
+    OptionSpec {
+        name: "offer_text",
+        kind: OptionKind::Bool,
+        default: "true",
+        local: false,
+        description: "offer to insert unknown commands as text",
+    },
+    OptionSpec {
+        name: "pager",
+        kind: OptionKind::Text,
+        default: "auto",
+        local: false,
+        description: "what pages long output: auto ($PAGER or builtin), builtin, off, or a command",
+    },
+    OptionSpec {
+        name: "startup_message",
+        kind: OptionKind::Bool,
+        default: "true",
+        local: false,
+        description: "show the startup message",
+    },
+    OptionSpec {
+        name: "syntax",
+        kind: OptionKind::Choice(&["auto", "off", "rust", "python", "shell", "markdown", "json", "toml"]),
+        default: "auto",
+        local: true,
+        description: "the language ~show highlights, auto to go by the file name or shebang",
+    },
+    OptionSpec {
+        name: "tab_width",
+        kind: OptionKind::Number,
+        default: "4",
+        local: true,
+        description: "how wide a tab is, and how far ~indent indents by default",
+    },
+    OptionSpec {
+        name: "text_width",
+        kind: OptionKind::Number,
+        default: "0",
+        local: true,
+        description: "wrap typed lines longer than this, 0 to never wrap",
+    },
+    OptionSpec {
+        name: "wrap",
+        kind: OptionKind::Bool,
+        default: "false",
+        local: true,
+        description: "wrap lines too long for the terminal in ~show, under the text rather than the line numbers",
+    },
+];
+
+/// Other names options go by.
+const OPTION_ALIASES: [(&str, &str); 1] = [("color", "colour")];
+
+/// Returns the option called `name`, if sued knows about it.
+pub fn find_option(name: &str) -> Option<&'static OptionSpec> {
+    let name = name.to_lowercase();
+    let name = OPTION_ALIASES.iter()
+        .find(|(alias, _)| *alias == name)
+        .map_or(name.as_str(), |(_, option_name)| option_name);
+    OPTIONS.iter().find(|option| option.name == name)
+}
+
+/// The value of an option.
+#[derive(Clone, PartialEq)]
+pub enum OptionValue {
+    Bool(bool),
+    Number(usize),
+    Text(String),
+}
+
+impl OptionValue {
+    /// Parses `raw` as a value for the option `spec`.
+    /// Returns a message saying what went wrong if it isn't a valid value.
+    pub fn parse(spec: &OptionSpec, raw: &str) -> Result<OptionValue, String> {
+        match &spec.kind {
+            OptionKind::Bool => match raw.to_lowercase().as_str() {
+                "on" | "true" | "yes" | "1" => Ok(OptionValue::Bool(true)),
+                "off" | "false" | "no" | "0" => Ok(OptionValue::Bool(false)),
+                _ => Err(format!("{} is on or off, not {}", spec.name, raw)),
+            },
+            OptionKind::Number => match raw.parse::<usize>() {
+                Ok(number) => Ok(OptionValue::Number(number)),
+                Err(_) => Err(format!("{} is a number, not {}", spec.name, raw)),
+            },
+            OptionKind::Choice(choices) => match choices.iter().find(|choice| choice.eq_ignore_ascii_case(raw)) {
+                Some(choice) => Ok(OptionValue::Text(choice.to_string())),
+                None => Err(format!("{} is one of {}, not {}", spec.name, choices.join(", "), raw)),
+            },
+            OptionKind::Text => Ok(OptionValue::Text(raw.to_string())),
+        }
+    }
+
+    pub fn as_bool(&self) -> bool {
+        matches!(self, OptionValue::Bool(true))
+    }
+
+    pub fn as_number(&self) -> usize {
+        match self {
+            OptionValue::Number(number) => *number,
+            _ => 0,
+        }
+    }
+
+    pub fn as_text(&self) -> &str {
+        match self {
+            OptionValue::Text(text) => text,
+            _ => "",
+        }
+    }
+}
+
+impl fmt::Display for OptionValue {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OptionValue::Bool(true) => write!(fmt, "on"),
+            OptionValue::Bool(false) => write!(fmt, "off"),
+            OptionValue::Number(number) => write!(fmt, "{}", number),
+            OptionValue::Text(text) => write!(fmt, "{}", text),
+        }
+    }
+}
+
+/// A set of option values.
+/// The editor has one with every option in it, and each buffer has one with just its local overrides.
+#[derive(Clone)]
+pub struct Options {
+    values: BTreeMap<&'static str, OptionValue>,
+}
+
+impl Options {
+    /// Returns every option at its default value.
+    pub fn defaults() -> Options {
+        let values = OPTIONS.iter()
+            .map(|spec| (spec.name, OptionValue::parse(spec, spec.default).expect("option defaults should be valid")))
+            .collect();
+        Options { values }
+    }
+
+    /// Returns a set with no options in it, for buffers that don't override anything.
+    pub fn empty() -> Options {
+        Options { values: BTreeMap::new() }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&OptionValue> {
+        self.values.get(name)
+    }
+
+    /// Sets the option called `name` to the parsed `raw` value.
+    /// If `local` is set, only options that can be set per buffer are accepted.
+    /// Returns the option's full name, or a message saying why it couldn't be set.
+    pub fn set(&mut self, name: &str, raw: &str, local: bool) -> Result<&'static str, String> {
+        let spec = find_option(name).ok_or_else(|| format!("{} isn't an option", name))?;
+        if local && !spec.local {
+            return Err(format!("{} can't be set for just one buffer, try ~set", spec.name));
+        }
+        let value = OptionValue::parse(spec, raw)?;
+        self.values.insert(spec.name, value);
+        Ok(spec.name)
+    }
+
+    /// Removes the option called `name`, so a buffer goes back to the editor's value.
+    pub fn unset(&mut self, name: &str) -> bool {
+        match find_option(name) {
+            Some(spec) => self.values.remove(spec.name).is_some(),
+            None => false,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+}
@@ -0,0 +1,58 @@
+//! Contains sued's output, which normally goes to the terminal, but is collected instead
+//! while server mode is handling a request, so it can be sent back to the client.
+//!
+//! This file is part of sued.
+//!
+//! Visit `main.rs` for context and usage.
+
+use std::cell::RefCell;
+
+thread_local! {
+    static CAPTURED: RefCell<Option<Vec<String>>> = const { RefCell::new(None) };
+}
+
+/// Prints a message like `println!`, unless output is being captured,
+/// in which case each line of the message is kept for `finish_capture`.
+macro_rules! say {
+    () => {
+        $crate::output::say(String::new())
+    };
+    ($($arg:tt)*) => {
+        $crate::output::say(format!($($arg)*))
+    };
+}
+
+/// Prints or captures `message`. Use `say!` instead of calling this directly.
+pub fn say(message: String) {
+    CAPTURED.with(|captured| match captured.borrow_mut().as_mut() {
+        Some(lines) => lines.extend(message.split('\n').map(String::from)),
+        None => println!("{}", message),
+    });
+}
+
+/// Starts capturing output, throwing away anything captured before.
+pub fn start_capture() {
+    CAPTURED.with(|captured| *captured.borrow_mut() = Some(Vec::new()));
+}
+
+/// Stops capturing output, and returns everything that was captured.
+pub fn finish_capture() -> Vec<String> {
+    CAPTURED.with(|captured| captured.borrow_mut().take().unwrap_or_default())
+}
+
+/// Returns whether output is being captured, which means it's not going to a terminal.
+pub fn capturing() -> bool {
+    CAPTURED.with(|captured| captured.borrow().is_some())
+}
+
+/// Sends everything captured so far to stderr, and carries on capturing.
+/// Does nothing if output isn't being captured, since it's already been printed.
+pub fn flush_to_stderr() {
+    CAPTURED.with(|captured| {
+        if let Some(lines) = captured.borrow_mut().as_mut() {
+            for line in lines.drain(..) {
+                eprintln!("{}", line);
+            }
+        }
+    });
+}
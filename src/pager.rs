@@ -0,0 +1,185 @@
+//! Contains sued's pager, which stops `~show` and `~print` scrolling a long buffer off the screen.
+//!
+//! This file is part of sued.
+//!
+//! Visit `main.rs` for context and usage.
+
+use std::env;
+use std::io::{self, IsTerminal, Write};
+use std::process::{Command, Stdio};
+use linefeed::DefaultTerminal;
+use linefeed::terminal::{RawRead, SignalSet, Terminal};
+use regex::Regex;
+use crate::functions as suedfn;
+use crate::output;
+
+/// Shows `lines`, a page at a time if there are more than fit in the terminal.
+/// `pager` is the `pager` option: `auto` uses `$PAGER` if it's set and the built-in pager if not,
+/// `builtin` always uses the built-in pager, `off` never pages, and anything else is a command to pipe the lines to.
+/// Output that isn't going to a terminal is never paged.
+pub fn page(lines: &[String], pager: &str) {
+    if pager == "off" || output::capturing() || !io::stdout().is_terminal() || !io::stdin().is_terminal() {
+        return print_lines(lines);
+    }
+    let Ok(terminal) = DefaultTerminal::new() else {
+        return print_lines(lines);
+    };
+    let height = match terminal.lock_write().size() {
+        Ok(size) if size.lines > 0 && lines.len() >= size.lines => size.lines,
+        _ => return print_lines(lines),
+    };
+
+    let command = match pager {
+        "auto" => env::var("PAGER").ok().filter(|command| !command.trim().is_empty()),
+        "builtin" => None,
+        command => Some(command.to_string()),
+    };
+    match command {
+        Some(command) => {
+            if let Err(e) = run_pager(&command, lines) {
+                say!("couldn't run {}, because {}", command, e);
+                print_lines(lines);
+            }
+        }
+        None => builtin_pager(&terminal, lines, height),
+    }
+}
+
+/// Returns how many columns wide the terminal is, or `None` if output isn't going to a terminal.
+pub fn terminal_width() -> Option<usize> {
+    if output::capturing() || !io::stdout().is_terminal() {
+        return None;
+    }
+    let terminal = DefaultTerminal::new().ok()?;
+    let size = terminal.lock_write().size().ok()?;
+    Some(size.columns).filter(|&columns| columns > 0)
+}
+
+fn print_lines(lines: &[String]) {
+    for line in lines {
+        say!("{}", line);
+    }
+}
+
+/// Pipes `lines` into the pager `command`, through the shell, and waits for it to finish.
+fn run_pager(command: &str, lines: &[String]) -> io::Result<()> {
+    let mut pager = Command::new(suedfn::shell());
+    pager.arg("-c").arg(command).stdin(Stdio::piped());
+    // The same as git does, so less shows colours, and doesn't bother paging if everything fits anyway
+    if env::var_os("LESS").is_none() {
+        pager.env("LESS", "FRX");
+    }
+    let mut pager = pager.spawn()?;
+    if let Some(mut stdin) = pager.stdin.take() {
+        for line in lines {
+            // The pager stops reading if it's quit early, which is fine
+            if writeln!(stdin, "{}", line).is_err() {
+                break;
+            }
+        }
+    }
+    pager.wait()?;
+    Ok(())
+}
+
+/// Shows `lines` a screen at a time, asking what to do next at the bottom of each screen.
+/// Space shows the next screen, enter the next line, `/` searches forward, `n` searches again, and `q` stops.
+fn builtin_pager(terminal: &DefaultTerminal, lines: &[String], height: usize) {
+    let page_size = height.saturating_sub(1).max(1);
+    let mut shown = 0;
+    let mut until = page_size.min(lines.len());
+    let mut search: Option<Regex> = None;
+
+    loop {
+        print_lines(&lines[shown..until]);
+        shown = until;
+        if shown >= lines.len() {
+            return;
+        }
+
+        let status = format!("-- {}% -- space for more, enter for a line, / to search, q to stop", shown * 100 / lines.len());
+        match read_key(terminal, &status) {
+            Some(b' ' | b'f') => until = (shown + page_size).min(lines.len()),
+            Some(b'\r' | b'\n' | b'j') => until = shown + 1,
+            Some(key @ (b'/' | b'n')) => {
+                if key == b'/' {
+                    search = read_search();
+                }
+                match &search {
+                    Some(pattern) => match lines[shown..].iter().position(|line| pattern.is_match(&strip_colour(line))) {
+                        Some(found) => {
+                            if found > 0 {
+                                say!("...skipping {} lines", found);
+                            }
+                            shown += found;
+                            until = (shown + page_size).min(lines.len());
+                        }
+                        None => say!("nothing after this matches {}", pattern),
+                    },
+                    None => say!("search for what?"),
+                }
+            }
+            _ => return,
+        }
+    }
+}
+
+/// Shows `status` at the bottom of the screen and waits for a key, without waiting for enter.
+/// Returns the first byte of whatever was pressed, or `None` if reading the terminal failed.
+fn read_key(terminal: &DefaultTerminal, status: &str) -> Option<u8> {
+    {
+        let mut writer = terminal.lock_write();
+        writer.write(status).ok()?;
+        writer.flush().ok()?;
+    }
+
+    let mut input = Vec::new();
+    let key = {
+        let mut reader = terminal.lock_read();
+        let state = reader.prepare(true, SignalSet::new()).ok()?;
+        let key = loop {
+            match reader.read(&mut input) {
+                Ok(RawRead::Bytes(_)) if !input.is_empty() => break Some(input[0]),
+                Ok(RawRead::Bytes(_) | RawRead::Resize(_)) => continue,
+                Ok(RawRead::Signal(_)) | Err(_) => break None,
+            }
+        };
+        reader.restore(state).ok()?;
+        key
+    };
+
+    let mut writer = terminal.lock_write();
+    writer.move_to_first_column().ok()?;
+    writer.clear_to_screen_end().ok()?;
+    writer.flush().ok()?;
+    key
+}
+
+/// Asks for a pattern to search for, which is a regex, or plain text if it isn't a valid regex.
+fn read_search() -> Option<Regex> {
+    print!("/");
+    io::stdout().flush().ok()?;
+    let mut pattern = String::new();
+    io::stdin().read_line(&mut pattern).ok()?;
+    let pattern = pattern.trim_end_matches(['\r', '\n']);
+    if pattern.is_empty() {
+        return None;
+    }
+    Regex::new(pattern).or_else(|_| Regex::new(&regex::escape(pattern))).ok()
+}
+
+/// Returns `line` without the colour codes `~show` adds, so searches only see the text.
+fn strip_colour(line: &str) -> String {
+    let mut stripped = String::with_capacity(line.len());
+    let mut rest = line;
+    while let Some(start) = rest.find("\x1b[") {
+        stripped.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        match after.find(|c: char| c.is_ascii_alphabetic()) {
+            Some(end) => rest = &after[end + 1..],
+            None => rest = "",
+        }
+    }
+    stripped.push_str(rest);
+    stripped
+}
@@ -0,0 +1,200 @@
+//! Contains sued's scripting, which runs Rhai scripts for `~script`, `~eval` and script commands.
+//!
+//! This file is part of sued.
+//!
+//! Visit `main.rs` for context and usage.
+
+use std::cell::RefCell;
+use std::fs;
+use std::path::PathBuf;
+use std::rc::Rc;
+use rhai::{Array, Dynamic, Engine, EvalAltResult, Scope};
+use rhai::module_resolvers::DummyModuleResolver;
+use crate::functions as suedfn;
+
+/// How many operations a script can do before sued stops it, so a script that loops forever doesn't hang the editor.
+const MAX_OPERATIONS: u64 = 50_000_000;
+
+/// How big a script's strings (in bytes), arrays and maps can get, so a script that keeps growing one
+/// gets stopped with an error instead of running sued out of memory. Arrays have room for a whole buffer of lines.
+const MAX_STRING_SIZE: usize = 16 * 1024 * 1024;
+const MAX_ARRAY_SIZE: usize = 4_000_000;
+const MAX_MAP_SIZE: usize = 100_000;
+
+/// The part of the buffer a script can see and change.
+/// Scripts work on a copy, which only replaces the real buffer if the script finishes without an error.
+#[derive(Clone)]
+pub struct ScriptBuffer {
+    pub lines: Vec<String>,
+    pub cursor: usize,
+    pub file_path: Option<String>,
+    pub tab_width: usize,
+    pub expand_tab: bool,
+}
+
+type ScriptResult<T> = Result<T, Box<EvalAltResult>>;
+
+/// A helper function for the script bindings.
+/// Returns the index of `line_number`, if that line is in the buffer.
+fn line_index(buffer: &ScriptBuffer, line_number: i64) -> ScriptResult<usize> {
+    match usize::try_from(line_number) {
+        Ok(line_number) if (1..=buffer.lines.len()).contains(&line_number) => Ok(line_number - 1),
+        _ => Err(format!("no line {}", line_number).into()),
+    }
+}
+
+/// Gives the `engine` functions for reading and changing the `buffer`, including some of sued's own commands.
+/// Line numbers start at 1, the same as everywhere else in sued.
+fn register_buffer(engine: &mut Engine, buffer: &Rc<RefCell<ScriptBuffer>>) {
+    let b = buffer.clone();
+    engine.register_fn("line_count", move || b.borrow().lines.len() as i64);
+
+    let b = buffer.clone();
+    engine.register_fn("line", move |line_number: i64| -> ScriptResult<String> {
+        let buffer = b.borrow();
+        Ok(buffer.lines[line_index(&buffer, line_number)?].clone())
+    });
+
+    let b = buffer.clone();
+    engine.register_fn("set_line", move |line_number: i64, text: &str| -> ScriptResult<()> {
+        let mut buffer = b.borrow_mut();
+        let index = line_index(&buffer, line_number)?;
+        buffer.lines[index] = text.to_string();
+        Ok(())
+    });
+
+    let b = buffer.clone();
+    engine.register_fn("insert_line", move |line_number: i64, text: &str| -> ScriptResult<()> {
+        let mut buffer = b.borrow_mut();
+        // Inserting just past the last line is how a script adds to the end
+        match usize::try_from(line_number) {
+            Ok(line_number) if (1..=buffer.lines.len() + 1).contains(&line_number) => {
+                buffer.lines.insert(line_number - 1, text.to_string());
+                Ok(())
+            }
+            _ => Err(format!("no line {}", line_number).into()),
+        }
+    });
+
+    let b = buffer.clone();
+    engine.register_fn("append", move |text: &str| {
+        b.borrow_mut().lines.push(text.to_string());
+    });
+
+    let b = buffer.clone();
+    engine.register_fn("delete_line", move |line_number: i64| -> ScriptResult<String> {
+        let mut buffer = b.borrow_mut();
+        let index = line_index(&buffer, line_number)?;
+        Ok(buffer.lines.remove(index))
+    });
+
+    let b = buffer.clone();
+    engine.register_fn("lines", move || -> Array {
+        b.borrow().lines.iter().map(|line| Dynamic::from(line.clone())).collect()
+    });
+
+    let b = buffer.clone();
+    engine.register_fn("set_lines", move |lines: Array| {
+        b.borrow_mut().lines = lines.into_iter().map(|line| line.to_string()).collect();
+    });
+
+    let b = buffer.clone();
+    engine.register_fn("cursor", move || b.borrow().cursor as i64);
+
+    let b = buffer.clone();
+    engine.register_fn("set_cursor", move |line_number: i64| -> ScriptResult<()> {
+        let mut buffer = b.borrow_mut();
+        let index = line_index(&buffer, line_number)?;
+        buffer.cursor = index + 1;
+        Ok(())
+    });
+
+    let b = buffer.clone();
+    engine.register_fn("file_path", move || b.borrow().file_path.clone().unwrap_or_default());
+
+    let b = buffer.clone();
+    engine.register_fn("find", move |pattern: &str| -> ScriptResult<Array> {
+        let regex = regex::Regex::new(pattern).map_err(|e| e.to_string())?;
+        Ok(b.borrow().lines.iter()
+            .enumerate()
+            .filter(|(_, line)| regex.is_match(line))
+            .map(|(index, _)| Dynamic::from(index as i64 + 1))
+            .collect())
+    });
+
+    let b = buffer.clone();
+    engine.register_fn("substitute", move |line_number: i64, pattern: &str, replacement: &str| -> ScriptResult<()> {
+        let mut buffer = b.borrow_mut();
+        let index = line_index(&buffer, line_number)?;
+        suedfn::substitute(&mut buffer.lines, index + 1, pattern, replacement);
+        Ok(())
+    });
+
+    let b = buffer.clone();
+    engine.register_fn("indent", move |line_number: i64, level: i64| -> ScriptResult<()> {
+        let mut buffer = b.borrow_mut();
+        let index = line_index(&buffer, line_number)?;
+        let (tab_width, expand_tab) = (buffer.tab_width, buffer.expand_tab);
+        suedfn::indent(&mut buffer.lines, index + 1, level as isize, tab_width, expand_tab);
+        Ok(())
+    });
+}
+
+/// Runs the Rhai `script` on a copy of the `buffer`, with `args` in the `args` variable.
+/// Scripts can't touch files, run programs or load other scripts, and are stopped if they run for too long or get too big.
+/// Returns the changed buffer and whatever the script evaluated to, or a message saying what went wrong.
+pub fn run_script(script: &str, buffer: ScriptBuffer, args: &[&str]) -> Result<(ScriptBuffer, Dynamic), String> {
+    let mut engine = Engine::new();
+    engine.set_module_resolver(DummyModuleResolver::new());
+    engine.set_max_operations(MAX_OPERATIONS);
+    engine.set_max_call_levels(64);
+    engine.set_max_expr_depths(64, 64);
+    engine.set_max_string_size(MAX_STRING_SIZE);
+    engine.set_max_array_size(MAX_ARRAY_SIZE);
+    engine.set_max_map_size(MAX_MAP_SIZE);
+    engine.on_print(|text| say!("{}", text));
+
+    let buffer = Rc::new(RefCell::new(buffer));
+    register_buffer(&mut engine, &buffer);
+
+    let mut scope = Scope::new();
+    let args: Array = args.iter().map(|arg| Dynamic::from(arg.to_string())).collect();
+    scope.push("args", args);
+
+    let result = engine.eval_with_scope::<Dynamic>(&mut scope, script).map_err(|e| e.to_string())?;
+    let buffer = buffer.borrow().clone();
+    Ok((buffer, result))
+}
+
+/// Returns the directory sued looks for script commands in.
+/// That's `$XDG_CONFIG_HOME/sued/scripts` on Linux, or the equivalent elsewhere.
+pub fn scripts_directory() -> Option<PathBuf> {
+    suedfn::config_file_path().and_then(|config_path| config_path.parent().map(|config_directory| config_directory.join("scripts")))
+}
+
+/// Returns the script for the command `name`, which is `name.rhai` in the scripts directory.
+/// Script names follow the same rules as alias names, so nothing outside the scripts directory can be run this way.
+pub fn find_script(name: &str) -> Option<PathBuf> {
+    if name.is_empty() || !name.chars().all(|c| c.is_alphanumeric() || c == '-' || c == '_') {
+        return None;
+    }
+    scripts_directory()
+        .map(|scripts_directory| scripts_directory.join(format!("{}.rhai", name)))
+        .filter(|script_path| script_path.is_file())
+}
+
+/// Returns the command names of every script in the scripts directory, for `~` and tab completion.
+pub fn script_names() -> Vec<String> {
+    let Some(entries) = scripts_directory().and_then(|scripts_directory| fs::read_dir(scripts_directory).ok()) else {
+        return Vec::new();
+    };
+    let mut names: Vec<String> = entries
+        .filter_map(|entry| {
+            let path = entry.ok()?.path();
+            let name = path.file_name()?.to_str()?.strip_suffix(".rhai")?.to_string();
+            find_script(&name).map(|_| name)
+        })
+        .collect();
+    names.sort();
+    names
+}
@@ -0,0 +1,250 @@
+//! Contains sued's server mode, which lets other programs drive sued with JSON-RPC
+//! instead of reading what it prints.
+//!
+//! This file is part of sued.
+//!
+//! Visit `main.rs` for context and usage.
+
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use serde_json::{json, Map, Value};
+use crate::functions as suedfn;
+use crate::options::Options;
+use crate::{output, EditorState, ExitStatus, FileBuffer};
+
+/// The request wasn't valid JSON.
+const PARSE_ERROR: i64 = -32700;
+/// The request was JSON, but not a JSON-RPC request.
+const INVALID_REQUEST: i64 = -32600;
+/// There's no method with the name the request asked for.
+const METHOD_NOT_FOUND: i64 = -32601;
+/// The method's parameters were missing or the wrong type.
+const INVALID_PARAMS: i64 = -32602;
+/// The request was fine, but sued couldn't do what it asked, like opening a file that isn't there.
+const EDITOR_ERROR: i64 = -32000;
+
+/// An error to send back to the client instead of a result.
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl Into<String>) -> RpcError {
+        RpcError { code, message: message.into() }
+    }
+}
+
+/// Serves JSON-RPC requests until the client asks sued to exit.
+/// Requests are read over stdio, or over a Unix domain socket at `socket_path` if there is one.
+/// Anything sued printed before the server started goes to stderr, so it doesn't get mixed up with the responses.
+pub fn serve(socket_path: Option<&str>, buffer: &mut FileBuffer, state: &mut EditorState) {
+    for line in output::finish_capture() {
+        eprintln!("{}", line);
+    }
+
+    match socket_path {
+        None => {
+            serve_connection(io::stdin().lock(), io::stdout().lock(), buffer, state);
+        }
+        Some(socket_path) => serve_socket(socket_path, buffer, state),
+    }
+}
+
+/// Accepts clients on a Unix domain socket at `socket_path`, one at a time.
+/// Every client works on the same buffer, and the socket is removed when sued exits.
+#[cfg(unix)]
+fn serve_socket(socket_path: &str, buffer: &mut FileBuffer, state: &mut EditorState) {
+    use std::os::unix::net::UnixListener;
+
+    let listener = match UnixListener::bind(socket_path) {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("couldn't listen on {}, because {}", socket_path, e);
+            return;
+        }
+    };
+    eprintln!("listening on {}", socket_path);
+
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("couldn't accept a client, because {}", e);
+                continue;
+            }
+        };
+        let reader = match stream.try_clone() {
+            Ok(reader) => BufReader::new(reader),
+            Err(e) => {
+                eprintln!("couldn't talk to a client, because {}", e);
+                continue;
+            }
+        };
+        if serve_connection(reader, stream, buffer, state) {
+            break;
+        }
+    }
+
+    fs::remove_file(socket_path).unwrap_or_default();
+}
+
+#[cfg(not(unix))]
+fn serve_socket(_socket_path: &str, _buffer: &mut FileBuffer, _state: &mut EditorState) {
+    eprintln!("sockets only work on unix-like systems, try --server on its own to use stdio");
+}
+
+/// Answers requests from one client, one per line, until it disconnects or asks sued to exit.
+/// Returns whether sued should exit.
+fn serve_connection(reader: impl BufRead, mut writer: impl Write, buffer: &mut FileBuffer, state: &mut EditorState) -> bool {
+    for line in reader.lines() {
+        let Ok(line) = line else {
+            break;
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let (response, exit) = handle_request(&line, buffer, state);
+        if let Some(response) = response {
+            if writeln!(writer, "{}", response).and_then(|_| writer.flush()).is_err() {
+                break;
+            }
+        }
+        if exit {
+            return true;
+        }
+    }
+    false
+}
+
+/// Handles one JSON-RPC request, returning the response to send back, if it needs one,
+/// and whether sued should exit. Notifications, which don't have an id, don't get a response.
+fn handle_request(line: &str, buffer: &mut FileBuffer, state: &mut EditorState) -> (Option<Value>, bool) {
+    let request: Value = match serde_json::from_str(line) {
+        Ok(request) => request,
+        Err(e) => return (Some(error_response(Value::Null, RpcError::new(PARSE_ERROR, e.to_string()))), false),
+    };
+
+    let id = request.get("id").cloned();
+    let Some(method) = request.get("method").and_then(Value::as_str) else {
+        return (Some(error_response(id.unwrap_or(Value::Null), RpcError::new(INVALID_REQUEST, "a request needs a method"))), false);
+    };
+    let empty = Map::new();
+    let params = match request.get("params") {
+        None | Some(Value::Null) => &empty,
+        Some(Value::Object(params)) => params,
+        Some(_) => {
+            return (Some(error_response(id.unwrap_or(Value::Null), RpcError::new(INVALID_PARAMS, "params have to be an object"))), false);
+        }
+    };
+
+    let exit = method == "exit";
+    let result = call(method, params, buffer, state);
+    let response = id.map(|id| match result {
+        Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+        Err(e) => error_response(id, e),
+    });
+    (response, exit)
+}
+
+fn error_response(id: Value, error: RpcError) -> Value {
+    json!({ "jsonrpc": "2.0", "id": id, "error": { "code": error.code, "message": error.message } })
+}
+
+/// Runs the `method` with its `params`, and returns its result.
+fn call(method: &str, params: &Map<String, Value>, buffer: &mut FileBuffer, state: &mut EditorState) -> Result<Value, RpcError> {
+    match method {
+        "open" => {
+            let file_path = string_param(params, "path")?.ok_or(RpcError::new(INVALID_PARAMS, "open needs a path"))?;
+            let file_path = crate::resolve_path(file_path, state).map_err(|e| RpcError::new(EDITOR_ERROR, e))?;
+            let contents = fs::read_to_string(&file_path)
+                .map_err(|e| RpcError::new(EDITOR_ERROR, format!("couldn't open {}, because {}", file_path, e)))?;
+            buffer.contents = contents.lines().map(String::from).collect();
+            buffer.file_path = Some(file_path.clone());
+            buffer.cursor = buffer.contents.len();
+            buffer.options = Options::empty();
+            crate::detect_line_ending(buffer, &file_path);
+            Ok(json!({ "path": file_path, "lines": buffer.contents.len() }))
+        }
+        "save" | "set-lines" if buffer.readonly => {
+            Err(RpcError::new(EDITOR_ERROR, format!("this buffer is read-only, so {} can't be used on it", method)))
+        }
+        "save" => {
+            let file_path = match string_param(params, "path")? {
+                Some(file_path) => file_path.to_string(),
+                None => buffer.file_path.clone().ok_or(RpcError::new(EDITOR_ERROR, "there's no file open, so save needs a path"))?,
+            };
+            let file_path = crate::resolve_path(&file_path, state).map_err(|e| RpcError::new(EDITOR_ERROR, e))?;
+            suedfn::write_file(&buffer.contents, &file_path, crate::save_options(buffer, state))
+                .map_err(|e| RpcError::new(EDITOR_ERROR, e))?;
+            buffer.file_path = Some(file_path.clone());
+            Ok(json!({ "path": file_path, "lines": buffer.contents.len() }))
+        }
+        "get-lines" => {
+            let (start_point, end_point) = range_params(params, buffer)?;
+            let lines = &buffer.contents[start_point - 1..end_point];
+            Ok(json!({ "start": start_point, "end": end_point, "lines": lines }))
+        }
+        "set-lines" => {
+            let (start_point, end_point) = range_params(params, buffer)?;
+            let lines: Vec<String> = match params.get("lines") {
+                Some(Value::Array(lines)) => lines.iter()
+                    .map(|line| line.as_str().map(String::from))
+                    .collect::<Option<Vec<String>>>()
+                    .ok_or(RpcError::new(INVALID_PARAMS, "lines have to be strings"))?,
+                _ => return Err(RpcError::new(INVALID_PARAMS, "set-lines needs an array of lines")),
+            };
+            let inserted = lines.len();
+            buffer.contents.splice(start_point - 1..end_point, lines);
+            buffer.cursor = (start_point - 1 + inserted).min(buffer.contents.len());
+            Ok(json!({ "lines": buffer.contents.len(), "cursor": buffer.cursor }))
+        }
+        "run-command" => {
+            let command = string_param(params, "command")?.ok_or(RpcError::new(INVALID_PARAMS, "run-command needs a command"))?;
+            output::start_capture();
+            let status = crate::process_line(command, buffer, state);
+            let output = output::finish_capture();
+            Ok(json!({
+                "output": output,
+                "exit": matches!(status, ExitStatus::Failure),
+                "cursor": buffer.cursor,
+                "lines": buffer.contents.len(),
+            }))
+        }
+        "status" => Ok(json!({
+            "path": buffer.file_path,
+            "cursor": buffer.cursor,
+            "lines": buffer.contents.len(),
+            "prefix": state.prefix,
+        })),
+        "exit" => Ok(Value::Null),
+        _ => Err(RpcError::new(METHOD_NOT_FOUND, format!("there's no method called {}", method))),
+    }
+}
+
+/// Returns the string parameter called `name`, if it's there.
+fn string_param<'a>(params: &'a Map<String, Value>, name: &str) -> Result<Option<&'a str>, RpcError> {
+    match params.get(name) {
+        None | Some(Value::Null) => Ok(None),
+        Some(Value::String(value)) => Ok(Some(value)),
+        Some(_) => Err(RpcError::new(INVALID_PARAMS, format!("{} has to be a string", name))),
+    }
+}
+
+/// Returns the range given by the `start` and `end` parameters, which default to the start and end of the buffer.
+/// A range can be empty, with `end` just before `start`, which is how `set-lines` inserts lines without replacing any.
+fn range_params(params: &Map<String, Value>, buffer: &FileBuffer) -> Result<(usize, usize), RpcError> {
+    let number_param = |name: &str, default: usize| match params.get(name) {
+        None | Some(Value::Null) => Ok(default),
+        Some(value) => value.as_u64()
+            .map(|number| number as usize)
+            .ok_or(RpcError::new(INVALID_PARAMS, format!("{} has to be a line number", name))),
+    };
+    let start_point = number_param("start", 1)?;
+    let end_point = number_param("end", buffer.contents.len())?;
+    if start_point < 1 || end_point > buffer.contents.len() || start_point > end_point + 1 {
+        return Err(RpcError::new(EDITOR_ERROR, format!("lines {} to {} aren't in the buffer", start_point, end_point)));
+    }
+    Ok((start_point, end_point))
+}